@@ -8,6 +8,36 @@ pub const IPV4_PRIVATE_NETWORKS: [&str; 4] = [
     "10.0.0.0/8",
 ];
 pub const IPV6_PRIVATE_NETWORKS: [&str; 2] = ["fe80::/10", "fc00::/7"];
+pub const IPV6_LINK_LOCAL_NETWORK: &str = "fe80::/10";
+pub const IPV6_LINK_LOCAL_MULTICAST: &str = "ff02::/16";
+
+pub const MDNS_IPV4_ADDRESS: &str = "224.0.0.251";
+pub const MDNS_IPV6_ADDRESS: &str = "ff02::fb";
+pub const MDNS_PORT: u16 = 5353;
+pub const SSDP_IPV4_ADDRESS: &str = "239.255.255.250";
+pub const SSDP_PORT: u16 = 1900;
+
+#[cfg(target_os = "macos")]
+pub const CONTINUITY_INTERFACES: [&str; 2] = ["awdl0", "llw0"];
+
+// special-purpose ranges never seen on the internet (rfc6890), private networks,
+// loopback, multicast and 240.0.0.0/4 (holds the broadcast address) are left out
+pub const IPV4_BOGON_NETWORKS: [&str; 7] = [
+    "0.0.0.0/8",
+    "100.64.0.0/10",
+    "192.0.0.0/24",
+    "192.0.2.0/24",
+    "198.18.0.0/15",
+    "198.51.100.0/24",
+    "203.0.113.0/24",
+];
+pub const IPV6_BOGON_NETWORKS: [&str; 5] = [
+    "::ffff:0:0/96",
+    "100::/64",
+    "2001:10::/28",
+    "2001:db8::/32",
+    "3ffe::/16",
+];
 
 pub const IPV4_NOT_ROUTABLE_MULTICASTS: [&str; 1] = ["224.0.0.0/24"];
 pub const IPV6_NOT_ROUTABLE_MULTICASTS: [&str; 4] =
@@ -16,4 +46,12 @@ pub const IPV6_NOT_ROUTABLE_MULTICASTS: [&str; 4] =
 #[cfg(unix)]
 pub const DEFAULT_CONF_DIR: &str = "~/.config/me.lucky.netlock/";
 pub const FIREWALL_CONF_FILE_NAME: &str = "firewall.conf";
+pub const FIREWALL_CONF_FILE_PREFIX: &str = "firewall_";
 pub const SETTINGS_CONF_FILE_NAME: &str = "settings.conf";
+pub const BACKUP_CONF_FILE_NAME: &str = "backup.conf";
+pub const PROFILES_DIR_NAME: &str = "profiles";
+pub const HISTORY_DIR_NAME: &str = "history";
+pub const DEFAULT_HISTORY_LIMIT: usize = 10;
+pub const BLOCKLIST_CACHE_PREFIX: &str = "blocklist_";
+pub const CONFIGURATION_SNIFF_LINES: usize = 32;
+pub const CONFIGURATION_SNIFF_BYTES: u64 = 64 * 1024;
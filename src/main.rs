@@ -1,50 +1,195 @@
-use std::collections::HashSet;
-use std::env::args;
+use std::collections::{HashMap, HashSet};
+use std::env::{args, var_os};
 use std::error::Error;
 use std::fmt::{self, Display, Formatter};
+use std::fs::read_to_string;
+use std::io::{stdout, IsTerminal};
 use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::slice::Iter;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::sleep;
+use std::time::Duration;
 
 use netlock::pf;
 
 const EXIT_SUCCESS: i32 = 0;
 const EXIT_USAGE: i32 = 2;
 
+const CLEAR_SCREEN: &str = "\x1b[2J\x1b[H";
+
+const DEFAULT_TABLE_PREFIX: &str = "netlock";
+const TABLE_SUFFIXES: [&str; 4] = ["_block", "_pass_in", "_pass_out", "_abusers"];
+// below this raising the ttl barely changes what outgoing packets reveal
+const MIN_USEFUL_TTL: u8 = 32;
+
 mod flag {
     pub const HELP: &str = "h";
     pub const VERSION: &str = "V";
     pub const VERBOSE: &str = "v";
+    pub const QUIET: &str = "q";
     pub const SKIPASS_LOOPBACK: &str = "0";
     pub const BLOCK_IPV6: &str = "6";
     pub const NO_LAN: &str = "l";
+    pub const ISOLATE_LAN: &str = "I";
+    pub const PASS_DHCP: &str = "H";
+    pub const LOCAL_LAN: &str = "n";
+    pub const PASS_SELF: &str = "m";
+    pub const PASS_CONTINUITY: &str = "w";
+    pub const REDACT: &str = "x";
+    pub const DRY_RUN: &str = "d";
+    pub const JSON: &str = "j";
+    pub const VALIDATE: &str = "k";
+    pub const RECURSIVE: &str = "z";
+    pub const RESOLVE: &str = "y";
+    pub const SURGICAL_DISABLE: &str = "e";
+    pub const REPLACE: &str = "R";
     pub const USE_ROUTING: &str = "r";
+    pub const GATEWAY_DNS: &str = "g";
+    pub const OPTIONS: &str = "F";
+    pub const OUTPUT: &str = "J";
     pub const CONFIG: &str = "c";
     pub const ANCHOR: &str = "a";
     pub const TTL: &str = "t";
+    pub const UNTIL: &str = "u";
+    pub const WATCH: &str = "W";
+    pub const COLOR: &str = "K";
+    pub const TABLE_PREFIX: &str = "T";
+    pub const ROUTE_TO: &str = "G";
     pub const SKIP: &str = "s";
     pub const PASS: &str = "p";
     pub const OWNER: &str = "O";
+    pub const PORT: &str = "N";
     pub const BLOCK: &str = "b";
+    pub const BLOCK_URL: &str = "U";
     pub const IN: &str = "i";
     pub const OUT: &str = "o";
     pub const FILE: &str = "f";
     pub const PRINT: &str = "P";
     pub const ENABLE: &str = "E";
     pub const DISABLE: &str = "D";
+    pub const RELOAD: &str = "Y";
     pub const LOAD: &str = "L";
     pub const STATUS: &str = "S";
+    pub const CHECK_DRIFT: &str = "C";
+    pub const DIFF: &str = "M";
+    pub const CHECK_EXPIRY: &str = "X";
+    pub const DIAGNOSTICS: &str = "B";
+    pub const LIST_ANCHORS: &str = "A";
+    pub const PRINT_PATHS: &str = "Q";
+    pub const KILL_STATES: &str = "Z";
+    // long only
+    pub const PFCTL: &str = "pfctl";
+    pub const PF_CONF: &str = "pf-conf";
+    pub const PLATFORM: &str = "platform";
+    pub const PROFILE: &str = "profile";
+    pub const SAVE_PROFILE: &str = "save-profile";
+    pub const TAG: &str = "tag";
+    pub const NO_QUICK: &str = "no-quick";
+    pub const FIRST_REMOTE: &str = "first-remote";
+    pub const FORCE: &str = "force";
+    pub const PASS_OUT_ON: &str = "pass-out-on";
+    pub const SERVICE_DISCOVERY: &str = "service-discovery";
+    pub const PASS_LOOPBACK: &str = "pass-loopback";
+    pub const BLOCK_IPV4: &str = "block-ipv4";
 }
 
+// (long, short, takes a value)
+const LONG_FLAGS: [(&str, &str, bool); 67] = [
+    ("help", flag::HELP, false),
+    ("version", flag::VERSION, false),
+    ("verbose", flag::VERBOSE, false),
+    ("quiet", flag::QUIET, false),
+    ("skipass-loopback", flag::SKIPASS_LOOPBACK, false),
+    ("use-routing", flag::USE_ROUTING, false),
+    ("gateway-dns", flag::GATEWAY_DNS, false),
+    ("block-ipv6", flag::BLOCK_IPV6, false),
+    ("no-lan", flag::NO_LAN, false),
+    ("isolate-lan", flag::ISOLATE_LAN, false),
+    ("pass-dhcp", flag::PASS_DHCP, false),
+    ("local-lan", flag::LOCAL_LAN, false),
+    ("pass-self", flag::PASS_SELF, false),
+    ("pass-continuity", flag::PASS_CONTINUITY, false),
+    ("replace", flag::REPLACE, false),
+    ("redact", flag::REDACT, false),
+    ("dry-run", flag::DRY_RUN, false),
+    ("json", flag::JSON, false),
+    ("validate", flag::VALIDATE, false),
+    ("recursive", flag::RECURSIVE, false),
+    ("resolve", flag::RESOLVE, false),
+    ("surgical-disable", flag::SURGICAL_DISABLE, false),
+    ("options", flag::OPTIONS, true),
+    ("output", flag::OUTPUT, true),
+    ("config", flag::CONFIG, true),
+    (flag::PFCTL, flag::PFCTL, true),
+    (flag::PF_CONF, flag::PF_CONF, true),
+    (flag::PLATFORM, flag::PLATFORM, true),
+    (flag::PROFILE, flag::PROFILE, true),
+    (flag::SAVE_PROFILE, flag::SAVE_PROFILE, true),
+    (flag::TAG, flag::TAG, true),
+    (flag::NO_QUICK, flag::NO_QUICK, false),
+    (flag::FIRST_REMOTE, flag::FIRST_REMOTE, false),
+    (flag::FORCE, flag::FORCE, false),
+    (flag::PASS_OUT_ON, flag::PASS_OUT_ON, true),
+    (flag::SERVICE_DISCOVERY, flag::SERVICE_DISCOVERY, false),
+    (flag::PASS_LOOPBACK, flag::PASS_LOOPBACK, false),
+    (flag::BLOCK_IPV4, flag::BLOCK_IPV4, false),
+    ("anchor", flag::ANCHOR, true),
+    ("ttl", flag::TTL, true),
+    ("until", flag::UNTIL, true),
+    ("watch", flag::WATCH, true),
+    ("color", flag::COLOR, true),
+    ("table-prefix", flag::TABLE_PREFIX, true),
+    ("route-to", flag::ROUTE_TO, true),
+    ("skip", flag::SKIP, true),
+    ("pass", flag::PASS, true),
+    ("owner", flag::OWNER, true),
+    ("block", flag::BLOCK, true),
+    ("in", flag::IN, true),
+    ("out", flag::OUT, true),
+    ("port", flag::PORT, true),
+    ("block-url", flag::BLOCK_URL, true),
+    ("file", flag::FILE, true),
+    ("print", flag::PRINT, false),
+    ("enable", flag::ENABLE, false),
+    ("disable", flag::DISABLE, false),
+    ("reload", flag::RELOAD, false),
+    ("load", flag::LOAD, false),
+    ("status", flag::STATUS, false),
+    ("check-drift", flag::CHECK_DRIFT, false),
+    ("diff", flag::DIFF, false),
+    ("check-expiry", flag::CHECK_EXPIRY, false),
+    ("diagnostics", flag::DIAGNOSTICS, false),
+    ("list-anchors", flag::LIST_ANCHORS, false),
+    ("print-paths", flag::PRINT_PATHS, false),
+    ("kill-states", flag::KILL_STATES, true),
+];
+
 mod metavar {
+    pub const OPTIONS_FILE: &str = "OPTIONS_FILE";
+    pub const OUTPUT_FILE: &str = "OUTPUT_FILE";
     pub const CONFIG_DIR: &str = "CONFIG_DIR";
+    pub const PFCTL: &str = "PFCTL";
+    pub const PF_CONF: &str = "PF_CONF";
+    pub const PLATFORM: &str = "PLATFORM";
+    pub const PROFILE: &str = "PROFILE";
+    pub const TAG: &str = "TAG";
     pub const ANCHOR: &str = "ANCHOR";
     pub const TTL: &str = "TTL";
+    pub const TIME: &str = "TIME";
+    pub const SECONDS: &str = "SECONDS";
+    pub const WHEN: &str = "WHEN";
+    pub const PREFIX: &str = "PREFIX";
+    pub const ROUTE: &str = "ROUTE";
+    pub const BINDING: &str = "BINDING";
     pub const INTERFACE: &str = "INTERFACE";
     pub const OWNER: &str = "OWNER";
+    pub const PORT: &str = "PORT";
     pub const DESTINATION: &str = "DESTINATION";
+    pub const URL: &str = "URL";
     pub const PATH: &str = "PATH";
+    pub const ADDRESS: &str = "ADDRESS";
 }
 
 #[derive(Clone, Copy)]
@@ -52,18 +197,34 @@ enum Command {
     Print,
     Enable,
     Disable,
+    Reload,
     Load,
     Status,
+    CheckDrift,
+    Diff,
+    CheckExpiry,
+    Diagnostics,
+    ListAnchors,
+    PrintPaths,
+    KillStates,
 }
 
 impl Command {
     fn iter() -> Iter<'static, Self> {
-        static COMMAND: [Command; 5] = [
+        static COMMAND: [Command; 13] = [
             Command::Print,
             Command::Enable,
             Command::Disable,
+            Command::Reload,
             Command::Load,
             Command::Status,
+            Command::CheckDrift,
+            Command::Diff,
+            Command::CheckExpiry,
+            Command::Diagnostics,
+            Command::ListAnchors,
+            Command::PrintPaths,
+            Command::KillStates,
         ];
         COMMAND.iter()
     }
@@ -77,8 +238,16 @@ impl FromStr for Command {
             flag::PRINT => Ok(Self::Print),
             flag::ENABLE => Ok(Self::Enable),
             flag::DISABLE => Ok(Self::Disable),
+            flag::RELOAD => Ok(Self::Reload),
             flag::LOAD => Ok(Self::Load),
             flag::STATUS => Ok(Self::Status),
+            flag::CHECK_DRIFT => Ok(Self::CheckDrift),
+            flag::DIFF => Ok(Self::Diff),
+            flag::CHECK_EXPIRY => Ok(Self::CheckExpiry),
+            flag::DIAGNOSTICS => Ok(Self::Diagnostics),
+            flag::LIST_ANCHORS => Ok(Self::ListAnchors),
+            flag::PRINT_PATHS => Ok(Self::PrintPaths),
+            flag::KILL_STATES => Ok(Self::KillStates),
             _ => Err(format!("Invalid command: `{}`", s)),
         }
     }
@@ -90,8 +259,16 @@ impl Display for Command {
             Self::Print => write!(f, "{}", flag::PRINT),
             Self::Enable => write!(f, "{}", flag::ENABLE),
             Self::Disable => write!(f, "{}", flag::DISABLE),
+            Self::Reload => write!(f, "{}", flag::RELOAD),
             Self::Load => write!(f, "{}", flag::LOAD),
             Self::Status => write!(f, "{}", flag::STATUS),
+            Self::CheckDrift => write!(f, "{}", flag::CHECK_DRIFT),
+            Self::Diff => write!(f, "{}", flag::DIFF),
+            Self::CheckExpiry => write!(f, "{}", flag::CHECK_EXPIRY),
+            Self::Diagnostics => write!(f, "{}", flag::DIAGNOSTICS),
+            Self::ListAnchors => write!(f, "{}", flag::LIST_ANCHORS),
+            Self::PrintPaths => write!(f, "{}", flag::PRINT_PATHS),
+            Self::KillStates => write!(f, "{}", flag::KILL_STATES),
         }
     }
 }
@@ -122,81 +299,229 @@ enum PrintDestination {
 }
 
 fn print_usage(to: PrintDestination) {
-    let usage = format!(
-        "{} [-{h}{V}] [-{v}{v}] [-{Q}{r}{q}{l}] [-{c} <{C}>] [-{a} <{A}>] [-{t} <{T}>]\n\
+    let mut usage = format!(
+        "{} [-{h}{V}] [-{v}{v} | -{qt}] [-{Q}{r}{g}{q}{l}{L}{dh}{n}{m}{w}{R}{x}{d}{j}{k}{z}{y}{sd}]\n\
+         \t[-{S} <{B}>] [-{ou} <{of}>] [-{c} <{C}>]\n\
+         \t[--{pc} <{PC}>] [--{pe} <{PE}>] [--{pl} <{PL}>]\n\
+         \t[--{pf} <{PF}>] [--{sp} <{PF}>] [--{tg} <{TG}>]\n\
+         \t[--{nq}] [--{fr}] [--{fo}] [--{sv}] [--{pb}] [--{b4}]\n\
+         \t[-{a} <{A}>] [-{t} <{T}>] [-{u} <{M}>]\n\
+         \t[-{H} <{E}>] [-{cl} <{wn}>] [-{K} <{X}>] [-{G} <{F}>]\n\
          \t[.. -{s} <{I}>] [.. -{p} <{I}>] [.. -{O} <{W}>]\n\
          \t[.. -{b} <{D}>] [.. -{i} <{D}>] [.. -{o} <{D}>]\n\
-         \t[.. -{f} <{P}>]\n\
+         \t[.. -{e} <{Z}>] [.. -{Y} <{J}>] [.. -{f} <{P}>]\n\
+         \t[.. --{po} <{BD}>]\n\
          \t-{{ {} }}\n\n\
          [-{h}] * Print help and exit\n\
          [-{V}] * Print version and exit\n\n\
          [-{v}] * Verbose level (2 - enable firewall logging)\n\
+         [-{qt}] * Quiet, print only errors and requested output\n\
          [-{Q}] * Skipass on loopback\n\
+         [--{pb}] * Pass on loopback with rules instead of skip (keeps scrub and logging, implies -{Q})\n\
          [-{r}] * Extend outgoing <{I}> and <{D}> from routing table\n\
+         [-{g}] * Pass dns to default gateway from routing table\n\
          [-{q}] * Block IPv6\n\
+         [--{b4}] * Block IPv4 (for ipv6-only tunnels)\n\
          [-{l}] * No lan\n\
+         [-{L}] * Isolate from lan (pass only dhcp)\n\
+         [-{dh}] * Pass dhcp\n\
+         [-{n}] * Pass lan only on local subnets from interfaces\n\
+         [--{sv}] * Pass mdns and ssdp service discovery (printers, casting) on lan\n\
+         [-{m}] * Pass to own addresses from interfaces\n\
+         [-{w}] * Pass continuity (airdrop, handoff) on awdl and llw\n\
+         [-{R}] * Replace <{I}>, <{W}>, <{Z}> and <{D}> lists instead of extending\n\
+         [-{x}] * Redact addresses in diagnostics\n\
+         [-{d}] * Dry run, log pfctl commands and writes instead of running them\n\
+         [-{j}] * Print status as json\n\
+         [-{k}] * Validate rules with pfctl before loading\n\
+         [--{fo}] * Enable or load rules passing nothing out (total lockout)\n\
+         [-{z}] * Scan <{P}> dirs recursively\n\
+         [--{fr}] * Pass only the first remote of .ovpn <{P}> unless remote-random is set\n\
+         [-{y}] * Resolve hostname <{D}> to addresses when building rules\n\
+         [-{sd}] * Disable removes only netlock rules and tables (without anchor)\n\
+         [-{S}] * Read long options from <{B}> ( long = value ), cli overrides file\n\
+         [-{ou}] * Print rules to <{of}> instead of stdout\n\
          [-{c}] * Path to <{C}> (default: {})\n\
+         [--{pc}] * Path to <{PC}> (default: {})\n\
+         [--{pe}] * Path to <{PE}> restored on disable (default: {})\n\
          [-{a}] * Use <{A}> (`{}` will be replaced with `{}`)\n\
          [-{t}] * Minimum outgoing <{T}>\n\
+         [-{u}] * Disable lock at <{M}> on check expiry\n\
+         [-{H}] * Watch status, refresh every <{E}>\n\
+         [-{cl}] * Color output <{wn}> ( {} | {} | {} ) (default: {})\n\
+         [--{pl}] * Write rules in <{PL}> pf syntax ( {} | {} | {} ) (default: {})\n\
+         [--{pf}] * Load rules from <{PF}> in <{C}>/{}/, flags override it\n\
+         [--{sp}] * Save rules with applied flags to <{PF}>, command is optional\n\
+         [-{K}] * Use <{X}> for table names (default: {})\n\
+         [-{G}] * Route outgoing <{D}> via <{F}>\n\
+         [--{tg}] * Tag tunnel egress with <{TG}>, log untagged outgoing blocks\n\
+         [--{nq}] * Interface, owner and destination passes without quick\n\
          [-{s}] * Skip on <{I}>\n\
          [-{p}] * Pass on <{I}>\n\
-         [-{O}] * Pass owned by <{W}> ( {U} | {}{U} | {}GROUP )\n\
+         [-{O}] * Pass owned by <{W}> ( {U} | {}{U} | {}GROUP ) {}/{} prefix for one direction, ids also as >=N, <=N, N:M\n\
          [-{b}] * Block <{D}>\n\
+         [-{Y}] * Block addresses from blocklist <{J}>, last fetched copy is kept in <{C}>\n\
          [-{i}] * Pass in from <{D}>\n\
          [-{o}] * Pass out to <{D}>\n\
+         [--{po}] * Pass out to <{D}> only on <{I}> ( {N}:{D} )\n\
+         [-{e}] * Pass out to <{Z}> on any destination\n\
          [-{f}] * Extend outgoing <{D}> from configuration <{P}>\n\n\
          [-{}] * Print rules and exit\n\
          [-{}] * Enable lock\n\
          [-{}] * Disable lock\n\
+         [-{}] * Reload lock, validate and swap rules without disabling\n\
          [-{}] * Load lock\n\
-         [-{}] * Show status\n\n\
+         [-{}] * Show status\n\
+         [-{}] * Check loaded rules for drift\n\
+         [-{}] * Print unified diff of loaded and generated rules\n\
+         [-{}] * Check expiry, disable lock if due\n\
+         [-{}] * Print diagnostics for bug reports\n\
+         [-{}] * List netlock anchors (containing `{}`)\n\
+         [-{}] * Print config, settings and pfctl paths\n\
+         [-{}] * Kill states from and to <{ad}> ( ip | network | host ), repeatable\n\n\
          {I}:\n\
          \r  ( {N} | {}{N} | {}{N} ) direction only on pass\n\n\
          {D}:\n\
          \r  ( ip | host | file )\n\n\
+         {F}:\n\
+         \r  ( {N}:GATEWAY ) route-to interface and gateway address\n\n\
+         {Z}:\n\
+         \r  ( NUMBER | tcp/NUMBER | udp/NUMBER ) tcp and udp if no proto\n\n\
+         {M}:\n\
+         \r  ( HH:MM[:SS] | +N[s|m|h|d] | @UNIX_TIME )\n\n\
          {P}:\n\
-         \r  ( dir | file ) only .ovpn is supported, dir scan not recursive",
+         \r  ( dir | file ) .ovpn, .conf and .wg, other files are recognized by content, hidden entries are skipped",
         &get_prog_name(),
         &to_choices_string(Command::iter()),
         &pf::DEFAULT_CONF_DIR,
+        &pf::Ctl::DEFAULT_CTL_PATH,
+        &pf::Ctl::DEFAULT_CONF_PATH,
         &pf::Manager::ANCHOR_REPLACE_FROM,
         &pf::Manager::ANCHOR_REPLACE_TO,
+        &ColorMode::AUTO,
+        &ColorMode::ALWAYS,
+        &ColorMode::NEVER,
+        &ColorMode::AUTO,
+        &pf::Platform::MACOS,
+        &pf::Platform::FREEBSD,
+        &pf::Platform::OPENBSD,
+        &pf::Platform::detect(),
+        &pf::PROFILES_DIR_NAME,
+        &DEFAULT_TABLE_PREFIX,
         &pf::Owner::USER,
         &pf::Owner::GROUP,
+        &pf::Direction::IN,
+        &pf::Direction::OUT,
         &Command::Print,
         &Command::Enable,
         &Command::Disable,
+        &Command::Reload,
         &Command::Load,
         &Command::Status,
+        &Command::CheckDrift,
+        &Command::Diff,
+        &Command::CheckExpiry,
+        &Command::Diagnostics,
+        &Command::ListAnchors,
+        &pf::Manager::ANCHOR_REPLACE_TO,
+        &Command::PrintPaths,
+        &Command::KillStates,
         &pf::Direction::IN,
         &pf::Direction::OUT,
         h = flag::HELP,
         V = flag::VERSION,
         v = flag::VERBOSE,
+        qt = flag::QUIET,
         Q = flag::SKIPASS_LOOPBACK,
         r = flag::USE_ROUTING,
+        g = flag::GATEWAY_DNS,
         q = flag::BLOCK_IPV6,
         l = flag::NO_LAN,
+        L = flag::ISOLATE_LAN,
+        dh = flag::PASS_DHCP,
+        n = flag::LOCAL_LAN,
+        m = flag::PASS_SELF,
+        w = flag::PASS_CONTINUITY,
+        R = flag::REPLACE,
+        x = flag::REDACT,
+        d = flag::DRY_RUN,
+        j = flag::JSON,
+        k = flag::VALIDATE,
+        z = flag::RECURSIVE,
+        y = flag::RESOLVE,
+        sd = flag::SURGICAL_DISABLE,
+        S = flag::OPTIONS,
+        ou = flag::OUTPUT,
         c = flag::CONFIG,
+        pc = flag::PFCTL,
+        pe = flag::PF_CONF,
+        pl = flag::PLATFORM,
+        pf = flag::PROFILE,
+        sp = flag::SAVE_PROFILE,
+        tg = flag::TAG,
+        nq = flag::NO_QUICK,
+        fr = flag::FIRST_REMOTE,
+        fo = flag::FORCE,
+        po = flag::PASS_OUT_ON,
+        sv = flag::SERVICE_DISCOVERY,
+        pb = flag::PASS_LOOPBACK,
+        b4 = flag::BLOCK_IPV4,
         a = flag::ANCHOR,
         t = flag::TTL,
+        u = flag::UNTIL,
+        H = flag::WATCH,
+        cl = flag::COLOR,
+        K = flag::TABLE_PREFIX,
+        G = flag::ROUTE_TO,
         s = flag::SKIP,
         p = flag::PASS,
         O = flag::OWNER,
+        e = flag::PORT,
         b = flag::BLOCK,
+        Y = flag::BLOCK_URL,
         i = flag::IN,
         o = flag::OUT,
         f = flag::FILE,
+        B = metavar::OPTIONS_FILE,
+        of = metavar::OUTPUT_FILE,
         C = metavar::CONFIG_DIR,
+        PC = metavar::PFCTL,
+        PE = metavar::PF_CONF,
+        PL = metavar::PLATFORM,
+        PF = metavar::PROFILE,
+        TG = metavar::TAG,
         A = metavar::ANCHOR,
         T = metavar::TTL,
+        M = metavar::TIME,
+        E = metavar::SECONDS,
+        wn = metavar::WHEN,
+        X = metavar::PREFIX,
+        F = metavar::ROUTE,
+        BD = metavar::BINDING,
         I = metavar::INTERFACE,
         W = metavar::OWNER,
+        Z = metavar::PORT,
         D = metavar::DESTINATION,
+        J = metavar::URL,
         P = metavar::PATH,
+        ad = metavar::ADDRESS,
         U = "USER",
         N = "NAME",
     );
+    usage.push_str("\n\nLONG:\n");
+    let width = LONG_FLAGS
+        .iter()
+        .map(|(s, _, _)| s.len())
+        .max()
+        .unwrap_or(0);
+    usage.push_str(
+        &LONG_FLAGS
+            .iter()
+            .filter(|(long, short, _)| long != short)
+            .map(|(long, short, _)| format!("\r  --{:width$} -{}", long, short, width = width))
+            .collect::<Vec<_>>()
+            .join("\n"),
+    );
     match to {
         PrintDestination::Stdout => println!("{}", &usage),
         PrintDestination::Stderr => eprintln!("{}", &usage),
@@ -216,6 +541,11 @@ impl<'a> Color<'_> {
 
 impl Display for Color<'_> {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if !IS_COLOR.load(Ordering::Relaxed) {
+            return match self {
+                Self::Red(s) | Self::Green(s) => write!(f, "{}", s),
+            };
+        }
         match self {
             Self::Red(s) => write!(f, "{}{}{}", Self::RED, s, Self::ENDC),
             Self::Green(s) => write!(f, "{}{}{}", Self::GREEN, s, Self::ENDC),
@@ -223,6 +553,44 @@ impl Display for Color<'_> {
     }
 }
 
+static IS_COLOR: AtomicBool = AtomicBool::new(false);
+
+#[derive(Default, Clone, Copy)]
+enum ColorMode {
+    // colors only on a terminal and without `NO_COLOR`
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl<'a> ColorMode {
+    const AUTO: &'a str = "auto";
+    const ALWAYS: &'a str = "always";
+    const NEVER: &'a str = "never";
+
+    fn is_color(&self) -> bool {
+        match self {
+            Self::Auto => stdout().is_terminal() && var_os("NO_COLOR").is_none(),
+            Self::Always => true,
+            Self::Never => false,
+        }
+    }
+}
+
+impl FromStr for ColorMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            Self::AUTO => Ok(Self::Auto),
+            Self::ALWAYS => Ok(Self::Always),
+            Self::NEVER => Ok(Self::Never),
+            _ => Err(format!("Invalid {}: `{}`", metavar::WHEN, s)),
+        }
+    }
+}
+
 fn process_status(status: &pf::Status, is_verbose: bool) -> Result<(), Box<dyn Error>> {
     let display_state = |v: bool| {
         if v {
@@ -245,8 +613,23 @@ fn process_status(status: &pf::Status, is_verbose: bool) -> Result<(), Box<dyn E
         &display_state(netlock_state),
         width = firewall.chars().count().max(netlock.chars().count()),
     );
+    if status.is_drifted() {
+        println!("{}\n", Color::Red("DRIFTED"));
+    }
     if is_verbose {
-        let rules = status.rules();
+        if let Some(states) = status.states() {
+            println!("STATES {}", states);
+        }
+        if !status.interfaces().is_empty() {
+            println!("INTERFACES {}", status.interfaces().join(" "));
+        }
+        println!();
+        let rules = status
+            .main_ruleset()
+            .map(|v| ("", v))
+            .into_iter()
+            .chain(status.anchors())
+            .collect::<Vec<_>>();
         if !rules.is_empty() {
             let max_len = rules
                 .iter()
@@ -285,38 +668,260 @@ fn process_status(status: &pf::Status, is_verbose: bool) -> Result<(), Box<dyn E
 #[derive(Default)]
 struct Opts {
     verbose: u8,
+    is_quiet: bool,
     is_skipass_loopback: bool,
     is_use_routing: bool,
+    is_pass_gateway_dns: bool,
     is_block_ipv6: bool,
     is_no_lan: bool,
+    is_isolate_lan: bool,
+    is_pass_dhcp: bool,
+    is_local_lan: bool,
+    is_pass_self: bool,
+    is_pass_continuity: bool,
+    is_replace: bool,
+    is_redact: bool,
+    is_dry_run: bool,
+    is_json: bool,
+    is_validate: bool,
+    is_recursive: bool,
+    is_resolve: bool,
+    is_surgical_disable: bool,
+    options_file: Option<PathBuf>,
+    ctl_path: Option<PathBuf>,
+    pf_conf_path: Option<PathBuf>,
+    platform: Option<pf::Platform>,
+    profile: Option<String>,
+    save_profile: Option<String>,
+    tag: Option<String>,
+    is_no_quick: bool,
+    is_first_remote: bool,
+    is_force: bool,
+    is_pass_service_discovery: bool,
+    is_pass_loopback: bool,
+    is_block_ipv4: bool,
+    interface_destinations: HashMap<String, HashSet<String>>,
+    output_file: Option<PathBuf>,
     conf_dir: Option<PathBuf>,
     anchor: Option<String>,
-    ttl: u8,
+    ttl: Option<u8>,
+    until: Option<String>,
+    watch: Option<u64>,
+    color: ColorMode,
+    table_prefix: Option<String>,
+    route_to: Option<(String, String)>,
     command: Option<Command>,
     skip: HashSet<String>,
     pass: HashSet<pf::Direction>,
     owners: HashSet<pf::Owner>,
+    ports: Vec<pf::PortSpec>,
     block: HashSet<String>,
+    block_urls: Vec<String>,
     destinations: HashSet<pf::Direction>,
     files: HashSet<PathBuf>,
+    kill_states: Vec<String>,
+}
+
+fn check_interface(interface: &pf::Direction) -> Result<(), Box<dyn Error>> {
+    if interface.is_address() {
+        return Err(format!(
+            "Invalid {}: `{}` looks like an address, did you mean -{}?",
+            metavar::INTERFACE,
+            interface.safe_unwrap(),
+            flag::OUT,
+        )
+        .into());
+    }
+    Ok(())
+}
+
+// every table built from the prefix has to fit pf's table name limit,
+// including the per-interface `_pass_out_<interface>` tables
+fn check_table_prefix<'a>(
+    prefix: &str,
+    interfaces: impl IntoIterator<Item = &'a String>,
+) -> Result<(), Box<dyn Error>> {
+    let suffix_len = TABLE_SUFFIXES
+        .iter()
+        .map(|s| s.len())
+        .chain(
+            interfaces
+                .into_iter()
+                .map(|s| TABLE_SUFFIXES[2].len() + 1 + s.len()),
+        )
+        .max()
+        .unwrap_or(0);
+    if prefix.is_empty()
+        || prefix.len() + suffix_len > pf::Rules::MAX_TABLE_NAME_LEN
+        || !prefix
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        return Err(format!("Invalid {}: `{}`", metavar::PREFIX, prefix).into());
+    }
+    Ok(())
+}
+
+// long only flags are kept as `--name`
+fn to_arg(short: &str) -> String {
+    if short.chars().count() > 1 {
+        format!("--{}", short)
+    } else {
+        format!("-{}", short)
+    }
+}
+
+// `--name` and `--name=value` become their short flags, short flags are passed as is
+fn expand_long_flags(argv: impl Iterator<Item = String>) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut expanded = vec![];
+    for arg in argv {
+        let long = match arg.strip_prefix("--") {
+            Some(s) => s,
+            None => {
+                expanded.push(arg);
+                continue;
+            }
+        };
+        let (name, value) = match long.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (long, None),
+        };
+        match LONG_FLAGS.iter().find(|(s, _, _)| *s == name) {
+            Some((_, short, is_value)) => {
+                expanded.push(to_arg(short));
+                match value {
+                    Some(value) if *is_value => expanded.push(value.into()),
+                    Some(_) => return Err(format!("Unexpected value: `{}`", arg).into()),
+                    None => {}
+                }
+            }
+            None => return Err(format!("Invalid argument: `{}`", arg).into()),
+        }
+    }
+    Ok(expanded)
+}
+
+// `long = value` per line, flags take `true`/`false` or a count, lists repeat the key
+fn read_options_file(path: &Path) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut argv = vec![];
+    for line in read_to_string(path)?.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = match line.split_once('=') {
+            Some((k, v)) => (k.trim(), v.trim()),
+            None => (line, ""),
+        };
+        let err_invalid = || format!("Invalid option in `{}`: `{}`", path.display(), line);
+        let (_, short, is_value) = LONG_FLAGS
+            .iter()
+            .filter(|(_, s, _)| ![flag::HELP, flag::VERSION, flag::OPTIONS].contains(s))
+            .find(|(s, _, _)| *s == key)
+            .ok_or_else(err_invalid)?;
+        let short = to_arg(short);
+        if *is_value {
+            argv.extend([short, value.into()]);
+            continue;
+        }
+        let count = match value {
+            "" | "true" => 1,
+            "false" => 0,
+            s => s.parse::<u8>().map_err(|_| err_invalid())?,
+        };
+        argv.extend((0..count).map(|_| short.clone()));
+    }
+    Ok(argv)
 }
 
 fn parse_args() -> Result<Opts, Box<dyn Error>> {
-    let mut argv = args().skip(1);
-    if argv.len() == 0 {
+    let argv = expand_long_flags(args().skip(1))?;
+    if argv.is_empty() {
         print_usage(PrintDestination::Stderr);
         return Err("Not enough arguments".into());
     }
+    let mut opts = parse_argv(argv.clone())?;
+    // defaults < file < cli, file values come first so later cli values win and lists merge
+    if let Some(path) = &opts.options_file {
+        let mut file_argv = read_options_file(path)?;
+        file_argv.extend(argv);
+        opts = parse_argv(file_argv)?;
+    }
+    if opts.verbose > 0 && opts.is_quiet {
+        return Err(format!(
+            "Invalid arguments: -{} and -{} are mutually exclusive",
+            flag::VERBOSE,
+            flag::QUIET,
+        )
+        .into());
+    }
+    if opts.command.is_some() || opts.save_profile.is_some() {
+        return Ok(opts);
+    }
+    Err(format!(
+        "Missing argument: -{{ {} }}",
+        &to_choices_string(Command::iter())
+    )
+    .into())
+}
+
+fn parse_argv(argv: Vec<String>) -> Result<Opts, Box<dyn Error>> {
+    let mut argv = argv.into_iter();
     let mut opts = Opts::default();
     let err_missing_arg = |s: &str| Err(format!("Missing argument: {}", s).into());
-    loop {
-        let arg = match argv.next() {
-            Some(s) => s,
-            None => break,
-        };
+    while let Some(arg) = argv.next() {
         if !arg.starts_with('-') {
             return Err(format!("Invalid argument: `{}`", arg).into());
         }
+        if let Some(long) = arg.strip_prefix("--") {
+            match long {
+                flag::PFCTL => match argv.next() {
+                    Some(s) => opts.ctl_path = Some(s.into()),
+                    None => return err_missing_arg(metavar::PFCTL),
+                },
+                flag::PF_CONF => match argv.next() {
+                    Some(s) => opts.pf_conf_path = Some(s.into()),
+                    None => return err_missing_arg(metavar::PF_CONF),
+                },
+                flag::PLATFORM => match argv.next() {
+                    Some(s) => opts.platform = Some(s.parse()?),
+                    None => return err_missing_arg(metavar::PLATFORM),
+                },
+                flag::PROFILE => match argv.next() {
+                    Some(s) => opts.profile = Some(s),
+                    None => return err_missing_arg(metavar::PROFILE),
+                },
+                flag::SAVE_PROFILE => match argv.next() {
+                    Some(s) => opts.save_profile = Some(s),
+                    None => return err_missing_arg(metavar::PROFILE),
+                },
+                flag::TAG => match argv.next() {
+                    Some(s) => opts.tag = Some(s),
+                    None => return err_missing_arg(metavar::TAG),
+                },
+                flag::NO_QUICK => opts.is_no_quick = true,
+                flag::FIRST_REMOTE => opts.is_first_remote = true,
+                flag::FORCE => opts.is_force = true,
+                flag::SERVICE_DISCOVERY => opts.is_pass_service_discovery = true,
+                flag::PASS_LOOPBACK => opts.is_pass_loopback = true,
+                flag::BLOCK_IPV4 => opts.is_block_ipv4 = true,
+                flag::PASS_OUT_ON => match argv.next() {
+                    Some(s) => match s.split_once(':') {
+                        Some((interface, destination)) if !interface.is_empty() => {
+                            check_interface(&pf::Direction::new(interface))?;
+                            opts.interface_destinations
+                                .entry(interface.into())
+                                .or_default()
+                                .insert(pf::normalize_destination(destination)?);
+                        }
+                        _ => return Err(format!("Invalid {}: `{}`", metavar::BINDING, s).into()),
+                    },
+                    None => return err_missing_arg(metavar::BINDING),
+                },
+                _ => return Err(format!("Invalid argument: `{}`", arg).into()),
+            }
+            continue;
+        }
         for sub_arg in arg.chars().skip(1).map(|c| c.to_string()) {
             match sub_arg.as_str() {
                 flag::HELP => {
@@ -328,10 +933,33 @@ fn parse_args() -> Result<Opts, Box<dyn Error>> {
                     exit(EXIT_SUCCESS);
                 }
                 flag::VERBOSE => opts.verbose += 1,
+                flag::QUIET => opts.is_quiet = true,
                 flag::SKIPASS_LOOPBACK => opts.is_skipass_loopback = true,
                 flag::USE_ROUTING => opts.is_use_routing = true,
+                flag::GATEWAY_DNS => opts.is_pass_gateway_dns = true,
                 flag::BLOCK_IPV6 => opts.is_block_ipv6 = true,
                 flag::NO_LAN => opts.is_no_lan = true,
+                flag::ISOLATE_LAN => opts.is_isolate_lan = true,
+                flag::PASS_DHCP => opts.is_pass_dhcp = true,
+                flag::LOCAL_LAN => opts.is_local_lan = true,
+                flag::PASS_SELF => opts.is_pass_self = true,
+                flag::PASS_CONTINUITY => opts.is_pass_continuity = true,
+                flag::REPLACE => opts.is_replace = true,
+                flag::REDACT => opts.is_redact = true,
+                flag::DRY_RUN => opts.is_dry_run = true,
+                flag::JSON => opts.is_json = true,
+                flag::VALIDATE => opts.is_validate = true,
+                flag::RECURSIVE => opts.is_recursive = true,
+                flag::RESOLVE => opts.is_resolve = true,
+                flag::SURGICAL_DISABLE => opts.is_surgical_disable = true,
+                flag::OPTIONS => match argv.next() {
+                    Some(s) => opts.options_file = Some(s.into()),
+                    None => return err_missing_arg(metavar::OPTIONS_FILE),
+                },
+                flag::OUTPUT => match argv.next() {
+                    Some(s) => opts.output_file = Some(s.into()),
+                    None => return err_missing_arg(metavar::OUTPUT_FILE),
+                },
                 flag::CONFIG => match argv.next() {
                     Some(s) => opts.conf_dir = Some(s.into()),
                     None => return err_missing_arg(metavar::CONFIG_DIR),
@@ -341,42 +969,105 @@ fn parse_args() -> Result<Opts, Box<dyn Error>> {
                     None => return err_missing_arg(metavar::ANCHOR),
                 },
                 flag::TTL => match argv.next() {
-                    Some(s) => opts.ttl = s.parse()?,
+                    Some(s) => match s.parse() {
+                        Ok(ttl) => opts.ttl = Some(ttl),
+                        Err(_) => {
+                            return Err(format!(
+                                "Invalid {}: `{}` (max: {})",
+                                metavar::TTL,
+                                s,
+                                u8::MAX,
+                            )
+                            .into())
+                        }
+                    },
                     None => return err_missing_arg(metavar::TTL),
                 },
+                flag::UNTIL => match argv.next() {
+                    Some(s) => opts.until = s.into(),
+                    None => return err_missing_arg(metavar::TIME),
+                },
+                flag::WATCH => match argv.next() {
+                    Some(s) => match s.parse() {
+                        Ok(seconds) if seconds > 0 => opts.watch = Some(seconds),
+                        _ => return Err(format!("Invalid {}: `{}`", metavar::SECONDS, s).into()),
+                    },
+                    None => return err_missing_arg(metavar::SECONDS),
+                },
+                flag::COLOR => match argv.next() {
+                    Some(s) => opts.color = s.parse()?,
+                    None => return err_missing_arg(metavar::WHEN),
+                },
+                flag::TABLE_PREFIX => match argv.next() {
+                    Some(s) => opts.table_prefix = s.into(),
+                    None => return err_missing_arg(metavar::PREFIX),
+                },
+                flag::ROUTE_TO => match argv.next() {
+                    Some(s) => match s.split_once(':') {
+                        Some((interface, gateway)) if !interface.is_empty() => {
+                            opts.route_to = Some((interface.into(), gateway.into()))
+                        }
+                        _ => return Err(format!("Invalid {}: `{}`", metavar::ROUTE, s).into()),
+                    },
+                    None => return err_missing_arg(metavar::ROUTE),
+                },
                 flag::SKIP => match argv.next() {
                     Some(s) => {
+                        check_interface(&pf::Direction::new(&s))?;
                         opts.skip.insert(s);
                     }
                     None => return err_missing_arg(metavar::INTERFACE),
                 },
                 flag::PASS => match argv.next() {
                     Some(s) => {
-                        opts.pass.insert(s.into());
+                        let interface = pf::Direction::new(s);
+                        check_interface(&interface)?;
+                        opts.pass.insert(interface);
                     }
                     None => return err_missing_arg(metavar::INTERFACE),
                 },
                 flag::OWNER => match argv.next() {
                     Some(s) => {
-                        opts.owners.insert(s.into());
+                        let owner = pf::Owner::new(s);
+                        owner.to_rule_item()?;
+                        opts.owners.insert(owner);
                     }
                     None => return err_missing_arg(metavar::OWNER),
                 },
+                flag::PORT => match argv.next() {
+                    Some(s) => {
+                        let port = s.parse::<pf::PortSpec>()?;
+                        if !opts.ports.contains(&port) {
+                            opts.ports.push(port);
+                        }
+                    }
+                    None => return err_missing_arg(metavar::PORT),
+                },
                 flag::BLOCK => match argv.next() {
                     Some(s) => {
-                        opts.block.insert(s);
+                        opts.block.insert(pf::normalize_destination(&s)?);
                     }
                     None => return err_missing_arg(metavar::DESTINATION),
                 },
+                flag::BLOCK_URL => match argv.next() {
+                    Some(s) => {
+                        if !opts.block_urls.contains(&s) {
+                            opts.block_urls.push(s);
+                        }
+                    }
+                    None => return err_missing_arg(metavar::URL),
+                },
                 flag::IN => match argv.next() {
                     Some(s) => {
-                        opts.destinations.insert(pf::Direction::new(s).to_in());
+                        opts.destinations
+                            .insert(pf::Direction::new(pf::normalize_destination(&s)?).to_in());
                     }
                     None => return err_missing_arg(metavar::DESTINATION),
                 },
                 flag::OUT => match argv.next() {
                     Some(s) => {
-                        opts.destinations.insert(pf::Direction::new(s).to_out());
+                        opts.destinations
+                            .insert(pf::Direction::new(pf::normalize_destination(&s)?).to_out());
                     }
                     None => return err_missing_arg(metavar::DESTINATION),
                 },
@@ -386,6 +1077,15 @@ fn parse_args() -> Result<Opts, Box<dyn Error>> {
                     }
                     None => return err_missing_arg(metavar::PATH),
                 },
+                flag::KILL_STATES => match argv.next() {
+                    Some(s) => {
+                        if !opts.kill_states.contains(&s) {
+                            opts.kill_states.push(s);
+                        }
+                        opts.command = Command::KillStates.into();
+                    }
+                    None => return err_missing_arg(metavar::ADDRESS),
+                },
                 s => match Command::from_str(s) {
                     Ok(cmd) => opts.command = cmd.into(),
                     err => {
@@ -395,65 +1095,264 @@ fn parse_args() -> Result<Opts, Box<dyn Error>> {
             }
         }
     }
-    if opts.command.is_some() {
-        return Ok(opts);
+    if let Some(prefix) = &opts.table_prefix {
+        check_table_prefix(prefix, opts.interface_destinations.keys())?;
     }
-    err_missing_arg(&format!("-{{ {} }}", &to_choices_string(Command::iter())))
+    Ok(opts)
 }
 
 type MainResult = Result<(), Box<dyn Error>>;
 
 fn update_rules(loader: &mut pf::Loader, opts: &Opts) -> MainResult {
+    if let Some(name) = &opts.profile {
+        #[cfg(feature = "serde")]
+        loader.load_profile(name)?;
+        #[cfg(not(feature = "serde"))]
+        return err_no_serde(flag::PROFILE, name);
+    }
     let manager = loader.manager();
     manager.is_log = opts.verbose > 0;
-    if opts.is_skipass_loopback {
+    let rules = manager.rules();
+    if let Some(prefix) = &opts.table_prefix {
+        let [block_table, in_table, out_table, overload_table] =
+            TABLE_SUFFIXES.map(|s| format!("{}{}", prefix, s));
+        rules.set_table_names(block_table, in_table, out_table);
+        if let Some(protection) = &mut rules.inbound_protection {
+            protection.overload_table_name = overload_table;
+        }
+    }
+    if let Some(platform) = opts.platform {
+        rules.platform = platform;
+    }
+    match opts.ttl {
+        Some(0) => eprintln!(
+            "Warning: -{} 0 writes no `scrub out` rule, outgoing ttl is not normalized",
+            flag::TTL,
+        ),
+        Some(ttl) if ttl < MIN_USEFUL_TTL => eprintln!(
+            "Warning: -{} {} is below {}, a minimum ttl this low has little effect",
+            flag::TTL,
+            ttl,
+            MIN_USEFUL_TTL,
+        ),
+        _ => {}
+    }
+    if let Some(ttl) = opts.ttl {
+        rules.min_ttl = ttl;
+    }
+    if opts.route_to.is_some() {
+        rules.route_to = opts.route_to.clone();
+    }
+    if opts.tag.is_some() {
+        rules.tag = opts.tag.clone();
+    }
+    if opts.is_no_quick {
+        rules.is_quick = false;
+    }
+    if opts.verbose > 1 {
+        rules.log = pf::LogSections::ALL;
+    }
+    // flags only switch on, so a loaded profile keeps its values
+    rules.is_block_ipv6 |= opts.is_block_ipv6;
+    rules.is_block_ipv4 |= opts.is_block_ipv4;
+    if rules.is_block_ipv4 && rules.is_block_ipv6 {
+        eprintln!(
+            "Warning: --{} with -{} blocks all ip traffic",
+            flag::BLOCK_IPV4,
+            flag::BLOCK_IPV6,
+        );
+    }
+    rules.is_resolve_hostnames |= opts.is_resolve;
+    if opts.is_no_lan {
+        rules.lan = None;
+    }
+    if opts.is_pass_service_discovery {
+        match &mut rules.lan {
+            Some(lan) => lan.is_pass_service_discovery = true,
+            None => eprintln!(
+                "Warning: --{} has no effect with -{}",
+                flag::SERVICE_DISCOVERY,
+                flag::NO_LAN,
+            ),
+        }
+    }
+    rules.is_isolate_lan |= opts.is_isolate_lan;
+    rules.is_pass_dhcp |= opts.is_pass_dhcp;
+    #[cfg(target_os = "macos")]
+    {
+        rules.is_pass_continuity |= opts.is_pass_continuity;
+    }
+    #[cfg(not(target_os = "macos"))]
+    if opts.is_pass_continuity {
+        return Err(format!("-{} is supported only on macOS", flag::PASS_CONTINUITY).into());
+    }
+    if opts.is_replace {
+        rules.skip_interfaces = opts.skip.clone();
+        rules.pass_interfaces = opts.pass.clone();
+        rules.pass_owners = opts.owners.clone();
+        rules.pass_out_ports = opts.ports.clone();
+        rules.block_destinations = opts.block.clone();
+        rules.pass_destinations = opts.destinations.clone();
+        rules.interface_destinations = opts.interface_destinations.clone();
+    } else {
+        rules.skip_interfaces.extend(opts.skip.iter().cloned());
+        rules.pass_interfaces.extend(opts.pass.iter().cloned());
+        rules.pass_owners.extend(opts.owners.iter().cloned());
+        rules.pass_out_ports.extend(opts.ports.iter().cloned());
+        rules.block_destinations.extend(opts.block.iter().cloned());
+        rules
+            .pass_destinations
+            .extend(opts.destinations.iter().cloned());
+        for (interface, destinations) in &opts.interface_destinations {
+            rules
+                .interface_destinations
+                .entry(interface.clone())
+                .or_default()
+                .extend(destinations.iter().cloned());
+        }
+    }
+    if opts.is_skipass_loopback || opts.is_pass_loopback {
         if let Some(anchor) = &opts.anchor {
             manager.set_anchor(anchor);
         }
+        if opts.is_pass_loopback {
+            manager.loopback_mode = pf::LoopbackMode::Pass;
+        }
         manager.set_skipass_loopback()?;
     }
     if opts.is_use_routing {
+        manager.is_pass_gateway_dns = opts.is_pass_gateway_dns;
         manager.extend_rules_from_routing_table()?;
     }
-    let rules = manager.rules();
-    rules.min_ttl = opts.ttl;
-    rules.is_enable_log = opts.verbose > 1;
-    rules.is_block_ipv6 = opts.is_block_ipv6;
-    if opts.is_no_lan {
-        rules.lan = None;
+    if opts.is_local_lan {
+        manager.extend_lan_from_interfaces()?;
+    }
+    if opts.is_pass_self {
+        manager.extend_self_from_interfaces()?;
+    }
+    manager.is_scan_recursive = opts.is_recursive;
+    if opts.is_first_remote {
+        manager.parsers().set_remote_policy(pf::RemotePolicy::First);
+    }
+    let summary =
+        manager.extend_rules_from_configuration_files(&opts.files.iter().collect::<Vec<_>>())?;
+    for (path, _) in summary.files().iter().filter(|(_, d)| d.is_empty()) {
+        eprintln!(
+            "Warning: no destinations from `{}`{}",
+            path.display(),
+            if manager.parsers().is_supported(path) {
+                ""
+            } else {
+                " (unrecognized format)"
+            },
+        );
+    }
+    if opts.verbose > 0 && !opts.files.is_empty() {
+        for (path, destinations) in summary.files() {
+            eprintln!(
+                "[configuration_files] path: `{}`, destinations: `{}`",
+                path.display(),
+                destinations.len(),
+            );
+        }
+        eprintln!(
+            "[configuration_files] files scanned: `{}`, destinations added: `{}`",
+            summary.files_scanned(),
+            summary.destinations_added(),
+        );
+    }
+    loader.extend_block_from_urls(&opts.block_urls)?;
+    if let Some(name) = &opts.save_profile {
+        #[cfg(feature = "serde")]
+        loader.save_profile(name)?;
+        #[cfg(not(feature = "serde"))]
+        return err_no_serde(flag::SAVE_PROFILE, name);
     }
-    rules.skip_interfaces.extend(opts.skip.iter().cloned());
-    rules.pass_interfaces.extend(opts.pass.iter().cloned());
-    rules.pass_owners = opts.owners.clone();
-    rules.block_destinations = opts.block.clone();
-    rules
-        .pass_destinations
-        .extend(opts.destinations.iter().cloned());
-    manager.extend_rules_from_configuration_files(&opts.files.iter().collect::<Vec<_>>())?;
     Ok(())
 }
 
+fn preflight(rules: &pf::Rules, is_force: bool) -> MainResult {
+    if let Err(warning) = rules.preflight() {
+        if !is_force {
+            return Err(format!("{}, use --{} to override", warning, flag::FORCE).into());
+        }
+        eprintln!("Warning: {}", warning);
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "serde"))]
+fn err_no_serde(flag: &str, name: &str) -> MainResult {
+    Err(format!("--{} `{}` requires the `serde` feature", flag, name).into())
+}
+
 fn _main() -> MainResult {
     let opts = match parse_args() {
         Ok(v) => v,
         Err(err) => {
-            eprintln!("{}", err.to_string());
+            eprintln!("{}", err);
             exit(EXIT_USAGE);
         }
     };
+    IS_COLOR.store(opts.color.is_color(), Ordering::Relaxed);
+    let ctl = pf::Ctl::try_new(
+        opts.ctl_path
+            .clone()
+            .unwrap_or_else(|| pf::Ctl::DEFAULT_CTL_PATH.into()),
+        opts.pf_conf_path
+            .clone()
+            .unwrap_or_else(|| pf::Ctl::DEFAULT_CONF_PATH.into()),
+    )?;
     let mut loader = match &opts.conf_dir {
-        Some(path) => pf::Loader::new(path, Default::default()),
-        None => Default::default(),
+        Some(path) => pf::Loader::new(path, pf::Manager::new(ctl, Default::default())),
+        None => pf::Loader::new(
+            pf::DEFAULT_CONF_DIR,
+            pf::Manager::new(ctl, Default::default()),
+        ),
     };
-    let print_ok = || println!("OK");
-    match opts.command.expect("opts.command is None") {
+    loader.manager().set_dry_run(opts.is_dry_run);
+    loader.manager().is_validate = opts.is_validate;
+    loader.set_surgical_disable(opts.is_surgical_disable);
+    let is_quiet = opts.is_quiet;
+    let print_ok = || {
+        if !is_quiet {
+            println!("OK")
+        }
+    };
+    let command = match opts.command {
+        Some(command) => command,
+        // only `--save-profile`
+        None => {
+            update_rules(&mut loader, &opts)?;
+            print_ok();
+            return Ok(());
+        }
+    };
+    match command {
         Command::Print => {
             update_rules(&mut loader, &opts)?;
-            print!("{}", &loader.manager().rules().build());
+            match &opts.output_file {
+                Some(path) => {
+                    loader.write_rules(path)?;
+                    print_ok();
+                }
+                None => print!("{}", &loader.manager().rules().build()?),
+            }
         }
-        Command::Enable => {
+        Command::Enable | Command::Reload => {
             update_rules(&mut loader, &opts)?;
-            loader.enable(opts.anchor)?;
+            preflight(loader.manager().rules(), opts.is_force)?;
+            if let Some(until) = &opts.until {
+                loader.set_expiry(Some(pf::parse_time(until)?));
+            }
+            if opts.is_dry_run {
+                print!("{}", &loader.manager().rules().build()?);
+            }
+            if let Command::Reload = command {
+                loader.reload(opts.anchor)?;
+            } else {
+                loader.enable(opts.anchor)?;
+            }
             print_ok();
         }
         Command::Disable => {
@@ -461,11 +1360,93 @@ fn _main() -> MainResult {
             print_ok();
         }
         Command::Load => {
+            if let Ok(conf) = read_to_string(loader.find_firewall_conf_path(opts.anchor.as_ref())) {
+                preflight(&pf::Rules::from_pf_conf(&conf), opts.is_force)?;
+            }
             loader.load(opts.anchor)?;
             print_ok();
         }
         Command::Status => {
-            process_status(&loader.get_status()?, opts.verbose > 0)?;
+            loader.manager().is_verbose_status = opts.verbose > 0;
+            let print_status = |loader: &mut pf::Loader| -> MainResult {
+                let status = loader.get_status()?;
+                if opts.is_json {
+                    println!("{}", &status.to_json());
+                    Ok(())
+                } else {
+                    process_status(&status, opts.verbose > 0)
+                }
+            };
+            match opts.watch {
+                // runs until interrupted, a failing poll is printed and retried
+                Some(seconds) => loop {
+                    print!("{}", CLEAR_SCREEN);
+                    if let Err(err) = print_status(&mut loader) {
+                        eprintln!("{}", err.to_string().trim_end());
+                    }
+                    sleep(Duration::from_secs(seconds));
+                },
+                None => print_status(&mut loader)?,
+            }
+        }
+        Command::CheckDrift => {
+            update_rules(&mut loader, &opts)?;
+            let diff = loader.check_drift()?;
+            if !diff.is_empty() {
+                for line in &diff {
+                    println!("{}", line);
+                }
+                return Err("Loaded rules drifted".into());
+            }
+            print_ok();
+        }
+        Command::Diff => {
+            update_rules(&mut loader, &opts)?;
+            for line in &loader.diff_rules()? {
+                println!("{}", line);
+            }
+        }
+        Command::CheckExpiry => {
+            if loader.check_expiry()? {
+                if !opts.is_quiet {
+                    println!("Expired, lock disabled");
+                }
+            } else {
+                print_ok();
+            }
+        }
+        Command::ListAnchors => {
+            for anchor in loader.manager().list_anchors()? {
+                println!("{}", anchor);
+            }
+        }
+        Command::PrintPaths => {
+            let conf_dir = loader.conf_dir().to_path_buf();
+            let firewall_conf = loader.find_firewall_conf_path(opts.anchor.as_ref());
+            let settings_conf = loader.get_settings_conf_path();
+            let backup_conf = loader.get_backup_conf_path();
+            let history_dir = loader.get_history_dir_path();
+            let ctl = loader.manager().ctl();
+            for (name, path) in &[
+                ("CONFIG_DIR", conf_dir.as_path()),
+                ("FIREWALL_CONF", &firewall_conf),
+                ("SETTINGS_CONF", &settings_conf),
+                ("BACKUP_CONF", &backup_conf),
+                ("HISTORY_DIR", &history_dir),
+                ("PFCTL", ctl.ctl_path()),
+                ("PF_CONF", ctl.conf_path()),
+            ] {
+                println!("{} {}", name, path.display());
+            }
+        }
+        Command::KillStates => {
+            for address in &opts.kill_states {
+                let count = loader.manager().kill_states(address)?;
+                println!("{} {}", address, count);
+            }
+        }
+        Command::Diagnostics => {
+            print!("{}", &loader.diagnostics(opts.is_redact));
         }
     }
     Ok(())
@@ -477,3 +1458,64 @@ fn main() -> MainResult {
         Err(err) => Err(err.to_string().trim_end().into()),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn update(args: &[&str]) -> pf::Loader {
+        let pf_conf =
+            std::env::temp_dir().join(format!("netlock-test-{}-pf.conf", std::process::id()));
+        std::fs::write(&pf_conf, "").unwrap();
+        let rules = pf::Rules::builder()
+            .add_pass_owner("u:1000")
+            .add_pass_destination("192.0.2.1")
+            .add_pass_interface("utun1")
+            .build();
+        let ctl = pf::Ctl::new(PathBuf::from("/bin/sh"), pf_conf);
+        let mut loader = pf::Loader::ephemeral(pf::Manager::new(ctl, rules));
+        let argv = args.iter().map(|s| s.to_string()).collect();
+        update_rules(&mut loader, &parse_argv(argv).unwrap()).unwrap();
+        loader
+    }
+
+    #[test]
+    fn lists_are_appended() {
+        let mut loader = update(&["-O", "u:1001", "-o", "192.0.2.2", "-p", "utun2"]);
+        let rules = loader.manager().rules();
+        assert_eq!(rules.pass_owners.len(), 2);
+        assert!(rules.pass_owners.contains(&pf::Owner::new("u:1000")));
+        assert_eq!(rules.pass_destinations.len(), 2);
+        assert!(rules
+            .pass_destinations
+            .contains(&pf::Direction::new("192.0.2.1")));
+        assert_eq!(rules.pass_interfaces.len(), 2);
+        assert!(rules.pass_interfaces.contains(&pf::Direction::new("utun1")));
+    }
+
+    #[test]
+    fn lists_are_replaced() {
+        let mut loader = update(&["-R", "-O", "u:1001", "-o", "192.0.2.2", "-p", "utun2"]);
+        let rules = loader.manager().rules();
+        assert!(rules.pass_owners == HashSet::from([pf::Owner::new("u:1001")]));
+        assert!(
+            rules.pass_destinations == HashSet::from([pf::Direction::new("192.0.2.2").to_out()])
+        );
+        assert!(rules.pass_interfaces == HashSet::from([pf::Direction::new("utun2")]));
+    }
+
+    #[test]
+    fn addresses_as_interfaces_suggest_out() {
+        for interface in ["1.2.3.4", "10.0.0.0/8"] {
+            let argv = vec!["-p".to_string(), interface.to_string()];
+            let err = parse_argv(argv).err().unwrap().to_string();
+            assert_eq!(
+                err,
+                format!(
+                    "Invalid INTERFACE: `{}` looks like an address, did you mean -o?",
+                    interface,
+                ),
+            );
+        }
+    }
+}
@@ -1,21 +1,43 @@
 use std::collections::{HashMap, HashSet};
+use std::env::temp_dir;
 use std::ffi::OsStr;
 use std::fmt::{self, Display, Formatter};
-use std::fs::{create_dir_all, write, File};
-use std::io::{self, LineWriter, Result as IoResult, Write as IoWrite};
-use std::net::{Ipv4Addr, Ipv6Addr};
+use std::fs::{create_dir_all, read_dir, read_to_string, remove_file, OpenOptions};
+use std::io::{self, ErrorKind, Result as IoResult, Write as IoWrite};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, ToSocketAddrs};
+use std::ops::BitOr;
+use std::os::unix::fs::OpenOptionsExt;
 use std::path::{Path, PathBuf};
 use std::process::Output;
+use std::str::FromStr;
+use std::time::Duration;
 
 use crate::gvars;
-use crate::tools::{get_destinations_from_configuration_files, get_useful_routing_table_info};
-use crate::utils::{exec, exec_stdin, read_lines, time, ExecResult, ExpandUser, IsExecutable};
+use crate::tools::{
+    fetch_blocklist, get_destinations_from_configuration_files, get_interfaces,
+    get_local_addresses, get_local_subnets, get_routing_table, get_useful_routing_table_info,
+    is_address, RoutingInfo,
+};
+use crate::utils::{
+    clear_go_permissions, diff_lines, exec_stdin_timeout, exec_timeout, fnv1a_64, is_expired,
+    json_escape, read_lines, redact_addresses, time, unified_diff, write_atomic, ExecResult,
+    ExpandUser, ExpandVars, IsExecutable,
+};
 
-pub use crate::gvars::DEFAULT_CONF_DIR;
-pub use crate::tools::{Direction, Owner};
+pub use crate::gvars::{DEFAULT_CONF_DIR, PROFILES_DIR_NAME};
+pub use crate::tools::{
+    get_destinations_from_ovpn_file, normalize_destination, parse_time, ConfigurationParser,
+    Direction, Owner, ParserRegistry, Remote, RemotePolicy,
+};
+pub use crate::utils::{ExecError, NetlockError, NetlockResult};
 
 pub struct Loader {
     conf_dir: PathBuf,
+    is_ephemeral: bool,
+    is_surgical_disable: bool,
+    expiry: Option<u64>,
+    firewall_conf_name: String,
+    history_limit: usize,
     manager: Manager,
 }
 
@@ -23,6 +45,13 @@ impl<'a> Loader {
     const SETTINGS_SEP: char = ':';
     const SETTINGS_MANAGER_STATE: &'a str = "MANAGER_STATE";
     const SETTINGS_MANAGER_ANCHOR: &'a str = "MANAGER_ANCHOR";
+    const SETTINGS_EXPIRY: &'a str = "EXPIRY";
+    const SETTINGS_BLOCK_TABLE: &'a str = "BLOCK_TABLE";
+    const SETTINGS_IN_TABLE: &'a str = "IN_TABLE";
+    const SETTINGS_OUT_TABLE: &'a str = "OUT_TABLE";
+    const SETTINGS_BACKUP: &'a str = "BACKUP";
+    const SETTINGS_FIREWALL_HASH: &'a str = "FIREWALL_HASH";
+    const SETTINGS_FIREWALL_CONF: &'a str = "FIREWALL_CONF";
     #[cfg(not(target_os = "macos"))]
     const SETTINGS_CTL_STATE: &'a str = "CTL_STATE";
     #[cfg(target_os = "macos")]
@@ -31,61 +60,425 @@ impl<'a> Loader {
     pub fn new(conf_dir: impl Into<PathBuf>, manager: Manager) -> Self {
         let conf_dir = conf_dir.into().expanduser();
         assert!(!conf_dir.starts_with("~"));
-        Self { conf_dir, manager }
+        Self {
+            conf_dir,
+            is_ephemeral: false,
+            is_surgical_disable: false,
+            expiry: None,
+            firewall_conf_name: gvars::FIREWALL_CONF_FILE_NAME.into(),
+            history_limit: gvars::DEFAULT_HISTORY_LIMIT,
+            manager,
+        }
+    }
+
+    // nothing is persisted, rules are loaded from memory and status is live
+    pub fn ephemeral(manager: Manager) -> Self {
+        Self {
+            conf_dir: PathBuf::new(),
+            is_ephemeral: true,
+            is_surgical_disable: false,
+            expiry: None,
+            firewall_conf_name: gvars::FIREWALL_CONF_FILE_NAME.into(),
+            history_limit: gvars::DEFAULT_HISTORY_LIMIT,
+            manager,
+        }
+    }
+
+    pub fn try_default() -> io::Result<Self> {
+        Ok(Self::new(gvars::DEFAULT_CONF_DIR, Manager::try_default()?))
     }
 
-    pub fn enable(&mut self, anchor: Option<impl AsRef<str>>) -> ExecResult<()> {
+    // the ruleset is also kept in `history/<unix time>-<n>.conf`, see `history`
+    pub fn enable(&mut self, anchor: Option<impl AsRef<str>>) -> NetlockResult<()> {
+        let expiry = self.expiry;
+        let rules = &self.manager.rules;
+        let (block_table, in_table, out_table) = (
+            rules.block_table_name.clone(),
+            rules.in_table_name.clone(),
+            rules.out_table_name.clone(),
+        );
         let _ = self.load_settings_conf();
-        let rules = &self.manager.rules.build();
+        self.expiry = expiry;
+        self.manager
+            .rules
+            .set_table_names(block_table, in_table, out_table);
+        let is_anchorless = match &anchor {
+            Some(anchor) => anchor.as_ref().is_empty(),
+            None => self.manager.anchor.is_empty(),
+        };
+        if is_anchorless && !self.manager.state {
+            self.backup_ruleset()?;
+        }
+        let rules = &self.manager.rules.build()?;
         self.manager.load(LoadFile::Stdin(rules), anchor)?;
+        self.firewall_conf_name = Self::format_firewall_conf_name(&self.manager.anchor);
         self.make_firewall_conf(Some(rules))?;
+        self.make_history_conf(rules)?;
         self.make_settings_conf()?;
         Ok(())
     }
 
-    pub fn disable(&mut self) -> ExecResult<()> {
+    // enable with validation forced, the new ruleset is loaded before the old anchor is reset
+    // so there is no gap, a failing validation leaves the loaded rules untouched
+    pub fn reload(&mut self, anchor: Option<impl AsRef<str>>) -> NetlockResult<()> {
+        let is_validate = self.manager.is_validate;
+        self.manager.is_validate = true;
+        let result = self.enable(anchor);
+        self.manager.is_validate = is_validate;
+        result
+    }
+
+    pub fn disable(&mut self) -> NetlockResult<()> {
         self.load_settings_conf()?;
-        self.manager.disable()?;
+        if self.is_surgical_disable {
+            let loaded = if self.is_ephemeral {
+                self.manager.rules.build()?
+            } else {
+                read_to_string(self.get_firewall_conf_path())?
+            };
+            self.manager.disable_surgical(&loaded)?;
+        } else {
+            self.manager.disable()?;
+        }
+        self.expiry = None;
+        self.manager.restore_conf_path = None;
         self.make_settings_conf()?;
         Ok(())
     }
 
-    pub fn load(&mut self, anchor: Option<impl AsRef<str>>) -> ExecResult<()> {
+    pub fn load(&mut self, anchor: Option<impl AsRef<str>>) -> NetlockResult<()> {
+        if self.is_ephemeral {
+            let rules = &self.manager.rules.build()?;
+            return self.manager.load(LoadFile::Stdin(rules), anchor);
+        }
         self.load_settings_conf()?;
-        self.manager
-            .load(LoadFile::Path(&self.get_firewall_conf_path()), anchor)?;
+        let conf_path = self.find_firewall_conf_path(anchor.as_ref());
+        self.manager.load(LoadFile::Path(&conf_path), anchor)?;
+        self.firewall_conf_name = conf_path
+            .file_name()
+            .map(|s| s.to_string_lossy().into())
+            .unwrap_or_default();
         self.make_settings_conf()?;
         Ok(())
     }
 
-    pub fn get_status(&mut self) -> ExecResult<Status> {
+    pub fn get_status(&mut self) -> NetlockResult<Status> {
+        if self.is_ephemeral {
+            return self.manager.get_live_status();
+        }
         let _ = self.load_settings_conf();
         self.manager.get_status()
     }
 
+    pub fn check_consistency(&mut self) -> NetlockResult<Consistency> {
+        let _ = self.load_settings_conf();
+        let state = self.manager.state;
+        let anchor = &self.manager.anchor;
+        let status = self.manager.get_live_status()?;
+        let live_state = if anchor.is_empty() {
+            status.netlock_state
+        } else {
+            status.rules.contains_key(anchor)
+        };
+        let mut drifts = vec![];
+        if state && !status.firewall_state {
+            drifts.push(Drift::FirewallDisabled);
+        }
+        if state && !live_state {
+            drifts.push(Drift::SettingsOnLiveOff);
+        } else if !state && live_state {
+            drifts.push(Drift::SettingsOffLiveOn);
+        }
+        if state
+            && !live_state
+            && status
+                .rules
+                .keys()
+                .any(|k| k != anchor && k.contains(Manager::ANCHOR_REPLACE_TO))
+        {
+            drifts.push(Drift::AnchorMismatch);
+        }
+        Ok(Consistency { drifts })
+    }
+
+    // pushes the saved firewall.conf back as is, settings are only touched on change
+    pub fn reapply(&mut self) -> NetlockResult<()> {
+        self.load_settings_conf()?;
+        let conf_path = self.get_firewall_conf_path();
+        if !conf_path.is_file() {
+            return Err(io::Error::new(
+                ErrorKind::NotFound,
+                format!("Firewall conf not found: `{}`", conf_path.display()),
+            )
+            .into());
+        }
+        let settings = (self.manager.state, self.get_ctl_state());
+        self.manager
+            .load(LoadFile::Path(&conf_path), None::<&str>)?;
+        if settings != (self.manager.state, self.get_ctl_state()) {
+            self.make_settings_conf()?;
+        }
+        if !self.manager.get_status()?.netlock_state {
+            return Err(io::Error::other("Rules are not loaded after reapply").into());
+        }
+        Ok(())
+    }
+
+    pub fn check_drift(&mut self) -> NetlockResult<Vec<String>> {
+        let _ = self.load_settings_conf();
+        self.manager.check_drift()
+    }
+
+    pub fn diff_rules(&mut self) -> NetlockResult<Vec<String>> {
+        let _ = self.load_settings_conf();
+        self.manager.diff_rules()
+    }
+
+    // disables the lock if it is enabled and `expiry` has passed, meant to be run periodically
+    pub fn check_expiry(&mut self) -> NetlockResult<bool> {
+        self.load_settings_conf()?;
+        match self.expiry {
+            Some(expiry) if self.manager.state && is_expired(expiry, time()) => {
+                self.disable()?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    // (unix time, path) of rulesets saved by enable, oldest first
+    pub fn history(&self) -> NetlockResult<Vec<(u64, PathBuf)>> {
+        let history_dir = self.get_history_dir_path();
+        if self.is_ephemeral || !history_dir.is_dir() {
+            return Ok(vec![]);
+        }
+        let mut entries = vec![];
+        for entry in read_dir(history_dir)? {
+            let path = entry?.path();
+            let key = path
+                .extension()
+                .filter(|&ext| ext == "conf")
+                .and(path.file_stem())
+                .and_then(|s| s.to_str())
+                .and_then(Self::parse_history_stem);
+            if let Some(key) = key {
+                entries.push((key, path));
+            }
+        }
+        entries.sort_unstable();
+        Ok(entries
+            .into_iter()
+            .map(|((time, _), path)| (time, path))
+            .collect())
+    }
+
+    pub fn history_limit(&self) -> usize {
+        self.history_limit
+    }
+
+    // older entries are removed on the next enable, 0 disables the history
+    pub fn set_history_limit(&mut self, history_limit: usize) {
+        self.history_limit = history_limit;
+    }
+
+    pub fn expiry(&self) -> Option<u64> {
+        self.expiry
+    }
+
+    pub fn set_expiry(&mut self, expiry: Option<u64>) {
+        self.expiry = expiry;
+    }
+
+    // built rules only, pfctl and settings are not touched, readable by the owner only
+    pub fn write_rules(&self, path: impl AsRef<Path>) -> NetlockResult<()> {
+        let path = path.as_ref();
+        let rules = self.manager.rules.build()?;
+        if self.log_dry_run(path) {
+            return Ok(());
+        }
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(path)?
+            .write_all(rules.as_bytes())?;
+        Ok(())
+    }
+
+    // rules with applied flags as json, `load_profile` replaces the current rules with them
+    #[cfg(feature = "serde")]
+    pub fn save_profile(&self, name: &str) -> NetlockResult<()> {
+        let path = self.get_profile_path(name)?;
+        let profile = serde_json::to_string_pretty(&self.manager.rules).map_err(io::Error::from)?;
+        if self.log_dry_run(&path) {
+            return Ok(());
+        }
+        create_dir_all(self.conf_dir.join(gvars::PROFILES_DIR_NAME))?;
+        write_atomic(&path, profile)?;
+        Ok(())
+    }
+
+    #[cfg(feature = "serde")]
+    pub fn load_profile(&mut self, name: &str) -> NetlockResult<()> {
+        let path = self.get_profile_path(name)?;
+        let profile = read_to_string(&path).map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("Profile not readable: `{}` ({})", path.display(), err),
+            )
+        })?;
+        self.manager.rules = serde_json::from_str(&profile).map_err(|err| {
+            NetlockError::Parse(format!("Invalid profile: `{}` ({})", path.display(), err))
+        })?;
+        Ok(())
+    }
+
+    // snapshots the main filter rules, so disable restores them over `pf.conf` when anchorless
+    pub fn backup_ruleset(&mut self) -> NetlockResult<()> {
+        if self.is_ephemeral {
+            return Ok(());
+        }
+        let backup_path = self.get_backup_conf_path();
+        if !self.log_dry_run(&backup_path) {
+            let ruleset = self.manager.ctl.show(ShowModifier::Rules, "", false)?;
+            create_dir_all(&self.conf_dir)?;
+            write_atomic(&backup_path, ruleset)?;
+        }
+        self.manager.restore_conf_path = Some(backup_path);
+        Ok(())
+    }
+
+    pub fn diagnostics(&mut self, is_redact: bool) -> Diagnostics {
+        let mut diagnostics = self.manager.diagnostics(is_redact);
+        let settings = if self.is_ephemeral {
+            Ok(String::new())
+        } else {
+            read_to_string(self.get_settings_conf_path()).map_err(|err| err.into())
+        };
+        diagnostics.push("SETTINGS", settings, is_redact);
+        diagnostics
+    }
+
+    pub fn is_ephemeral(&self) -> bool {
+        self.is_ephemeral
+    }
+
+    pub fn is_surgical_disable(&self) -> bool {
+        self.is_surgical_disable
+    }
+
+    // disable removes only netlock rules and tables from the main ruleset instead of
+    // reloading the backup or `pf.conf`
+    pub fn set_surgical_disable(&mut self, is_surgical_disable: bool) {
+        self.is_surgical_disable = is_surgical_disable;
+    }
+
     pub fn manager(&mut self) -> &mut Manager {
         &mut self.manager
     }
 
+    pub fn conf_dir(&self) -> &Path {
+        &self.conf_dir
+    }
+
+    pub fn extend_block_from_urls(&mut self, urls: &[impl AsRef<str>]) -> NetlockResult<()> {
+        let cache_dir = if self.is_ephemeral {
+            temp_dir()
+        } else {
+            self.conf_dir.clone()
+        };
+        self.manager.extend_block_from_urls(urls, &cache_dir)
+    }
+
     fn make_firewall_conf(&self, content: Option<&str>) -> io::Result<()> {
+        if self.is_ephemeral || self.log_dry_run(&self.get_firewall_conf_path()) {
+            return Ok(());
+        }
         create_dir_all(&self.conf_dir)?;
         let conf_path = &self.get_firewall_conf_path();
         match content {
-            Some(rules) => write(conf_path, rules),
-            None => write(conf_path, &self.manager.rules.build()),
+            Some(rules) => write_atomic(conf_path, rules),
+            None => write_atomic(conf_path, self.manager.rules.build()?),
+        }
+    }
+
+    fn make_history_conf(&self, rules: &str) -> NetlockResult<()> {
+        if self.is_ephemeral || self.history_limit == 0 {
+            return Ok(());
+        }
+        let history_dir = self.get_history_dir_path();
+        // rulesets enabled within the same second are told apart by the counter
+        let now = time();
+        let mut n = 0;
+        let conf_path = loop {
+            let path = history_dir.join(format!("{}-{}.conf", now, n));
+            if !path.exists() {
+                break path;
+            }
+            n += 1;
+        };
+        if self.log_dry_run(&conf_path) {
+            return Ok(());
+        }
+        create_dir_all(&history_dir)?;
+        clear_go_permissions(&history_dir)?;
+        write_atomic(&conf_path, rules)?;
+        clear_go_permissions(&conf_path)?;
+        for (_, path) in self.history()?.iter().rev().skip(self.history_limit) {
+            remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    // `<unix time>-<n>`, entries written before the counter are just `<unix time>`
+    fn parse_history_stem(stem: &str) -> Option<(u64, u64)> {
+        match stem.split_once('-') {
+            Some((time, n)) => Some((time.parse().ok()?, n.parse().ok()?)),
+            None => Some((stem.parse().ok()?, 0)),
         }
     }
 
     fn make_settings_conf(&self) -> io::Result<()> {
+        if self.is_ephemeral || self.log_dry_run(&self.get_settings_conf_path()) {
+            return Ok(());
+        }
         create_dir_all(&self.conf_dir)?;
-        let conf_path = &self.get_settings_conf_path();
-        let mut file = LineWriter::new(File::create(conf_path)?);
+        let mut settings = String::new();
         for (k, v) in &[
             (
                 Self::SETTINGS_MANAGER_STATE,
                 &self.manager.state.to_string(),
             ),
             (Self::SETTINGS_MANAGER_ANCHOR, &self.manager.anchor),
+            (
+                Self::SETTINGS_EXPIRY,
+                &self.expiry.map(|v| v.to_string()).unwrap_or_default(),
+            ),
+            (
+                Self::SETTINGS_BLOCK_TABLE,
+                &self.manager.rules.block_table_name,
+            ),
+            (Self::SETTINGS_IN_TABLE, &self.manager.rules.in_table_name),
+            (Self::SETTINGS_OUT_TABLE, &self.manager.rules.out_table_name),
+            (
+                Self::SETTINGS_BACKUP,
+                &self
+                    .manager
+                    .restore_conf_path
+                    .as_ref()
+                    .map(|p| p.display().to_string())
+                    .unwrap_or_default(),
+            ),
+            (
+                Self::SETTINGS_FIREWALL_HASH,
+                &self
+                    .manager
+                    .ruleset_hash
+                    .map(|v| format!("{:016x}", v))
+                    .unwrap_or_default(),
+            ),
+            (Self::SETTINGS_FIREWALL_CONF, &self.firewall_conf_name),
             #[cfg(not(target_os = "macos"))]
             (
                 Self::SETTINGS_CTL_STATE,
@@ -94,45 +487,139 @@ impl<'a> Loader {
             #[cfg(target_os = "macos")]
             (Self::SETTINGS_CTL_TOKEN, &self.manager.ctl.token),
         ] {
-            writeln!(&mut file, "{}{}{}", k, Self::SETTINGS_SEP, v)?;
+            settings.push_str(&format!("{}{}{}\n", k, Self::SETTINGS_SEP, v));
         }
-        Ok(())
+        write_atomic(self.get_settings_conf_path(), settings)
     }
 
     fn load_settings_conf(&mut self) -> io::Result<()> {
-        for line in read_lines(&self.get_settings_conf_path())? {
+        if self.is_ephemeral {
+            return Ok(());
+        }
+        for line in read_lines(self.get_settings_conf_path())? {
             let line = line?;
+            let line = line.trim();
             if line.starts_with('#') {
                 continue;
             }
-            let option = line.split(Self::SETTINGS_SEP).collect::<Vec<_>>();
-            if option.len() != 2 {
-                continue;
-            }
-            match option[0] {
+            let (key, value) = match line.split_once(Self::SETTINGS_SEP) {
+                Some((k, v)) => (k.trim(), v.trim()),
+                None => continue,
+            };
+            match key {
                 Self::SETTINGS_MANAGER_STATE => {
-                    self.manager.state = option[1].parse().unwrap_or(self.manager.state)
+                    self.manager.state = value.parse().unwrap_or(self.manager.state)
+                }
+                Self::SETTINGS_MANAGER_ANCHOR => self.manager.anchor = value.into(),
+                Self::SETTINGS_EXPIRY => self.expiry = value.parse().ok(),
+                Self::SETTINGS_BLOCK_TABLE if !value.is_empty() => {
+                    self.manager.rules.block_table_name = value.into()
+                }
+                Self::SETTINGS_IN_TABLE if !value.is_empty() => {
+                    self.manager.rules.in_table_name = value.into()
+                }
+                Self::SETTINGS_OUT_TABLE if !value.is_empty() => {
+                    self.manager.rules.out_table_name = value.into()
+                }
+                Self::SETTINGS_BACKUP => {
+                    self.manager.restore_conf_path =
+                        Some(value.into()).filter(|_| !value.is_empty())
+                }
+                Self::SETTINGS_FIREWALL_HASH => {
+                    self.manager.ruleset_hash = u64::from_str_radix(value, 16).ok()
+                }
+                Self::SETTINGS_FIREWALL_CONF if !value.is_empty() && !value.contains('/') => {
+                    self.firewall_conf_name = value.into()
                 }
-                Self::SETTINGS_MANAGER_ANCHOR => self.manager.anchor = option[1].into(),
                 #[cfg(not(target_os = "macos"))]
                 Self::SETTINGS_CTL_STATE => {
-                    self.manager.ctl.state = option[1].parse().unwrap_or(self.manager.ctl.state);
+                    self.manager.ctl.state = value.parse().unwrap_or(self.manager.ctl.state);
                 }
                 #[cfg(target_os = "macos")]
-                Self::SETTINGS_CTL_TOKEN => self.manager.ctl.token = option[1].into(),
+                Self::SETTINGS_CTL_TOKEN => self.manager.ctl.token = value.into(),
                 _ => {}
             }
         }
         Ok(())
     }
 
-    fn get_firewall_conf_path(&self) -> PathBuf {
-        self.conf_dir.join(gvars::FIREWALL_CONF_FILE_NAME)
+    fn log_dry_run(&self, path: &Path) -> bool {
+        let is_dry_run = self.manager.is_dry_run();
+        if is_dry_run {
+            eprintln!("[dry_run] write `{}`", path.display());
+        }
+        is_dry_run
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    fn get_ctl_state(&self) -> String {
+        self.manager.ctl.state.to_string()
+    }
+
+    #[cfg(target_os = "macos")]
+    fn get_ctl_state(&self) -> String {
+        self.manager.ctl.token.clone()
+    }
+
+    // anchors kept side by side must not overwrite each other's saved rules
+    fn format_firewall_conf_name(anchor: &str) -> String {
+        if anchor.is_empty() {
+            return gvars::FIREWALL_CONF_FILE_NAME.into();
+        }
+        let name = anchor
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' || c == '.' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect::<String>();
+        format!("{}{}.conf", gvars::FIREWALL_CONF_FILE_PREFIX, name)
+    }
+
+    // rules of the last enabled or loaded anchor
+    pub fn get_firewall_conf_path(&self) -> PathBuf {
+        self.conf_dir.join(&self.firewall_conf_name)
+    }
+
+    // rules saved for `anchor`, or for the last enabled or loaded one if none is given
+    pub fn find_firewall_conf_path(&mut self, anchor: Option<impl AsRef<str>>) -> PathBuf {
+        let _ = self.load_settings_conf();
+        match anchor {
+            Some(anchor) => self.conf_dir.join(Self::format_firewall_conf_name(
+                &self.manager.format_anchor(anchor.as_ref()),
+            )),
+            None => self.get_firewall_conf_path(),
+        }
     }
 
-    fn get_settings_conf_path(&self) -> PathBuf {
+    pub fn get_settings_conf_path(&self) -> PathBuf {
         self.conf_dir.join(gvars::SETTINGS_CONF_FILE_NAME)
     }
+
+    pub fn get_backup_conf_path(&self) -> PathBuf {
+        self.conf_dir.join(gvars::BACKUP_CONF_FILE_NAME)
+    }
+
+    pub fn get_history_dir_path(&self) -> PathBuf {
+        self.conf_dir.join(gvars::HISTORY_DIR_NAME)
+    }
+
+    pub fn get_profile_path(&self, name: &str) -> NetlockResult<PathBuf> {
+        if name.is_empty() || name.starts_with('.') || name.contains('/') {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid profile: `{}`", name),
+            )
+            .into());
+        }
+        Ok(self
+            .conf_dir
+            .join(gvars::PROFILES_DIR_NAME)
+            .join(format!("{}.json", name)))
+    }
 }
 
 impl Default for Loader {
@@ -144,7 +631,10 @@ impl Default for Loader {
 pub struct Status {
     firewall_state: bool,
     netlock_state: bool,
+    is_drifted: bool,
     rules: HashMap<String, String>,
+    states: Option<usize>,
+    interfaces: Vec<String>,
 }
 
 impl Status {
@@ -156,15 +646,182 @@ impl Status {
         self.netlock_state
     }
 
+    // loaded rules no longer hash to what was loaded on enable, e.g. edited by another tool
+    pub fn is_drifted(&self) -> bool {
+        self.is_drifted
+    }
+
     pub fn rules(&self) -> &HashMap<String, String> {
         &self.rules
     }
+
+    // only collected with `Manager::is_verbose_status`
+    pub fn states(&self) -> Option<usize> {
+        self.states
+    }
+
+    pub fn interfaces(&self) -> &[String] {
+        &self.interfaces
+    }
+
+    pub fn main_ruleset(&self) -> Option<&str> {
+        self.rules.get("").map(|s| s.as_str())
+    }
+
+    pub fn anchors(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.rules
+            .iter()
+            .filter(|(k, _)| !k.is_empty())
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut rules = self.rules.iter().collect::<Vec<_>>();
+        rules.sort();
+        format!(
+            "{{\"drifted\": {}, \"firewall\": {}, \"interfaces\": [{}], \"netlock\": {}, \"rules\": {{{}}}, \"states\": {}}}",
+            self.is_drifted,
+            self.firewall_state,
+            self.interfaces
+                .iter()
+                .map(|s| json_escape(s))
+                .collect::<Vec<_>>()
+                .join(", "),
+            self.netlock_state,
+            rules
+                .iter()
+                .map(|(k, v)| format!("{}: {}", json_escape(k), json_escape(v)))
+                .collect::<Vec<_>>()
+                .join(", "),
+            self.states
+                .map(|v| v.to_string())
+                .unwrap_or_else(|| "null".into()),
+        )
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub enum Drift {
+    FirewallDisabled,
+    SettingsOnLiveOff,
+    SettingsOffLiveOn,
+    AnchorMismatch,
+}
+
+impl Display for Drift {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::FirewallDisabled => write!(f, "settings say enabled, firewall is disabled"),
+            Self::SettingsOnLiveOff => write!(f, "settings say enabled, rules are not loaded"),
+            Self::SettingsOffLiveOn => write!(f, "settings say disabled, rules are loaded"),
+            Self::AnchorMismatch => write!(f, "rules are loaded into another anchor"),
+        }
+    }
+}
+
+pub struct Consistency {
+    drifts: Vec<Drift>,
+}
+
+impl Consistency {
+    pub fn is_consistent(&self) -> bool {
+        self.drifts.is_empty()
+    }
+
+    pub fn drifts(&self) -> &[Drift] {
+        &self.drifts
+    }
+}
+
+pub struct Diagnostics {
+    sections: Vec<(String, String)>,
+}
+
+impl Diagnostics {
+    pub fn sections(&self) -> &[(String, String)] {
+        &self.sections
+    }
+
+    pub fn section(&self, name: &str) -> Option<&str> {
+        self.sections
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn push(&mut self, name: &str, content: ExecResult<String>, is_redact: bool) {
+        let content =
+            content.unwrap_or_else(|err| format!("error: {}", err.to_string().trim_end()));
+        let content = if is_redact {
+            redact_addresses(&content)
+        } else {
+            content
+        };
+        self.sections.push((name.into(), content));
+    }
+}
+
+impl Display for Diagnostics {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for (name, content) in &self.sections {
+            writeln!(f, "# {}", name)?;
+            writeln!(f, "{}", content.trim_end())?;
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct ConfigurationSummary {
+    files: Vec<(PathBuf, Vec<Remote>)>,
+    destinations_added: usize,
+}
+
+impl ConfigurationSummary {
+    pub fn files_scanned(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn destinations_added(&self) -> usize {
+        self.destinations_added
+    }
+
+    pub fn files(&self) -> &[(PathBuf, Vec<Remote>)] {
+        &self.files
+    }
+}
+
+#[derive(Default)]
+pub enum ProfileMode {
+    #[default]
+    Merged,
+    Anchors,
+}
+
+// `set skip` is the bsd idiom, pf ignores loopback entirely: no scrub, no state and
+// nothing to log, so a rule can't restrict it either. explicit passes keep loopback
+// visible in status and logs at the cost of evaluating the ruleset for local traffic
+#[derive(Default)]
+pub enum LoopbackMode {
+    #[default]
+    Skip,
+    Pass,
 }
 
 pub struct Manager {
     state: bool,
     anchor: String,
     pub is_log: bool,
+    pub is_pass_gateway_dns: bool,
+    pub is_scan_recursive: bool,
+    pub is_validate: bool,
+    pub is_verbose_status: bool,
+    pub loopback: String,
+    pub loopback_mode: LoopbackMode,
+    pub profile_mode: ProfileMode,
+    parsers: ParserRegistry,
+    restore_conf_path: Option<PathBuf>,
+    ruleset_hash: Option<u64>,
     ctl: Ctl,
     rules: Rules,
 }
@@ -172,32 +829,105 @@ pub struct Manager {
 impl<'a> Manager {
     pub const ANCHOR_REPLACE_FROM: &'a str = "$";
     pub const ANCHOR_REPLACE_TO: &'a str = "248.netlock";
+    pub const DEFAULT_LOOPBACK: &'a str = "lo";
+    pub const PROFILE_PREFIX: &'a str = "profile_";
+    pub const DIFF_LIVE_NAME: &'a str = "live";
+    pub const DIFF_BUILT_NAME: &'a str = "generated";
 
     pub fn new(ctl: Ctl, rules: Rules) -> Self {
         Self {
             state: false,
             anchor: "".into(),
             is_log: false,
+            is_pass_gateway_dns: false,
+            is_scan_recursive: false,
+            is_validate: false,
+            is_verbose_status: false,
+            loopback: Self::DEFAULT_LOOPBACK.into(),
+            loopback_mode: Default::default(),
+            profile_mode: Default::default(),
+            parsers: Default::default(),
+            restore_conf_path: None,
+            ruleset_hash: None,
             ctl,
             rules,
         }
     }
 
-    pub fn enable(&mut self, new_anchor: Option<impl AsRef<str>>) -> ExecResult<()> {
-        self.load(LoadFile::Stdin(&self.rules.build()), new_anchor)
+    pub fn try_default() -> io::Result<Self> {
+        Ok(Self::new(Ctl::try_default()?, Default::default()))
     }
 
-    pub fn disable(&mut self) -> ExecResult<()> {
+    pub fn enable(&mut self, new_anchor: Option<impl AsRef<str>>) -> NetlockResult<()> {
+        self.load(LoadFile::Stdin(&self.rules.build()?), new_anchor)
+    }
+
+    pub fn disable(&mut self) -> NetlockResult<()> {
         self.disable_firewall()?;
         self.reset(&self.anchor)?;
         self.state = false;
+        self.ruleset_hash = None;
+        Ok(())
+    }
+
+    // anchorless only, drop the rules of `loaded` (as parsed by pfctl, labeled or on its tables)
+    // from the main ruleset and delete its tables, foreign rules stay loaded
+    pub fn disable_surgical(&mut self, loaded: &str) -> NetlockResult<()> {
+        if !self.anchor.is_empty() {
+            return self.disable();
+        }
+        self.disable_firewall()?;
+        let own = self.ctl.parse(loaded, "")?;
+        let own = own.lines().map(|s| s.trim()).collect::<HashSet<_>>();
+        let tables = loaded
+            .lines()
+            .filter_map(|s| s.trim().strip_prefix("table <")?.split_once('>'))
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+        let is_own = |line: &str| {
+            own.contains(line)
+                || tables.iter().any(|t| line.contains(&format!("<{}>", t)))
+                || line
+                    .rsplit_once("label ")
+                    .is_some_and(|(_, s)| Rules::LABELS.contains(&s.trim().trim_matches('"')))
+        };
+        let mut foreign = String::new();
+        for line in self.ctl.show(ShowModifier::Rules, "", false)?.lines() {
+            let line = line.trim();
+            if !line.is_empty() && !is_own(line) {
+                if self.is_log {
+                    eprintln!("[disable_surgical] keep: `{}`", line);
+                }
+                foreign.push_str(line);
+                foreign.push('\n');
+            }
+        }
+        self.ctl.load_filter_rules(&foreign)?;
+        for table in tables {
+            self.ctl
+                .exec_table(table, TableCommand::Flush, &[] as &[&str], "")?;
+            self.ctl
+                .exec_table(table, TableCommand::Kill, &[] as &[&str], "")?;
+        }
+        self.state = false;
+        self.ruleset_hash = None;
         Ok(())
     }
 
-    pub fn get_status(&self) -> ExecResult<Status> {
-        let mut netlock_state = self.state;
+    pub fn get_status(&self) -> NetlockResult<Status> {
+        self.get_status_with_state(self.state)
+    }
+
+    // ignore the persisted state, trust only what pfctl reports
+    pub fn get_live_status(&self) -> NetlockResult<Status> {
+        self.get_status_with_state(true)
+    }
+
+    fn get_status_with_state(&self, state: bool) -> NetlockResult<Status> {
+        let mut netlock_state = state;
         let mut rules = HashMap::new();
-        let main_ruleset = self.ctl.show(ShowModifier::Rules, "", false)?;
+        let mut cache = ShowCache::new(&self.ctl);
+        let main_ruleset = cache.show(ShowModifier::Rules, "", false)?;
         if !main_ruleset.is_empty() {
             let mr_anchor = "";
             rules.insert(mr_anchor.to_string(), main_ruleset);
@@ -225,49 +955,125 @@ impl<'a> Manager {
                     netlock_state &= has_block_table && has_in_table && has_out_table;
                 }
                 if netlock_state {
-                    let mut has_block_table = false;
-                    let mut has_in_table = false;
-                    let mut has_out_table = false;
-                    for table in self
-                        .ctl
-                        .show(ShowModifier::Tables, mr_anchor, false)?
-                        .split_whitespace()
-                    {
-                        if !has_block_table && table == self.rules.block_table_name {
-                            has_block_table = true;
-                        } else if !has_in_table && table == self.rules.in_table_name {
-                            has_in_table = true;
-                        } else if !has_out_table && table == self.rules.out_table_name {
-                            has_out_table = true;
-                        }
-                        if has_block_table && has_in_table && has_out_table {
-                            break;
-                        }
-                    }
-                    netlock_state &= has_block_table && has_in_table && has_out_table;
+                    netlock_state = self.has_tables(&mut cache, mr_anchor)?;
                 }
             } else {
-                for anchor in self
-                    .ctl
+                for anchor in cache
                     .show(ShowModifier::Anchors, "", true)?
                     .split_whitespace()
                 {
-                    let ruleset = self.ctl.show(ShowModifier::Rules, anchor, false)?;
+                    let ruleset = cache.show(ShowModifier::Rules, anchor, false)?;
                     if !ruleset.is_empty() {
                         rules.insert(anchor.into(), ruleset);
                     }
                 }
+                // saved state is not trusted if the anchor was flushed or removed behind our back
+                if netlock_state {
+                    netlock_state = rules.contains_key(&self.anchor)
+                        && self.has_tables(&mut cache, &self.anchor)?;
+                }
             }
         } else {
             netlock_state = false;
         }
+        let is_drifted = netlock_state
+            && self.ruleset_hash.is_some_and(|hash| {
+                rules.get(&self.anchor).map(|s| Self::hash_ruleset(s)) != Some(hash)
+            });
+        let mut states = None;
+        let mut interfaces = vec![];
+        if self.is_verbose_status {
+            states = Some(
+                cache
+                    .show(ShowModifier::States, "", false)?
+                    .lines()
+                    .filter(|s| !s.trim().is_empty())
+                    .count(),
+            );
+            interfaces = cache
+                .show(ShowModifier::Interfaces(""), "", false)?
+                .split_whitespace()
+                .filter(|&s| s != "all")
+                .map(|s| s.into())
+                .collect();
+        }
         Ok(Status {
             firewall_state: self.ctl.is_enabled()?,
             netlock_state,
+            is_drifted,
             rules,
+            states,
+            interfaces,
         })
     }
 
+    fn has_tables(&self, cache: &mut ShowCache, anchor: &str) -> NetlockResult<bool> {
+        let mut has_block_table = false;
+        let mut has_in_table = false;
+        let mut has_out_table = false;
+        for table in cache
+            .show(ShowModifier::Tables, anchor, false)?
+            .split_whitespace()
+        {
+            if !has_block_table && table == self.rules.block_table_name {
+                has_block_table = true;
+            } else if !has_in_table && table == self.rules.in_table_name {
+                has_in_table = true;
+            } else if !has_out_table && table == self.rules.out_table_name {
+                has_out_table = true;
+            }
+            if has_block_table && has_in_table && has_out_table {
+                break;
+            }
+        }
+        Ok(has_block_table && has_in_table && has_out_table)
+    }
+
+    // sources are collected independently, a failing one is recorded in place of its output
+    pub fn diagnostics(&self, is_redact: bool) -> Diagnostics {
+        let mut diagnostics = Diagnostics { sections: vec![] };
+        diagnostics.push(
+            "INFO",
+            self.ctl.show(ShowModifier::Info, "", false),
+            is_redact,
+        );
+        diagnostics.push(
+            "RULES",
+            self.ctl.show(ShowModifier::Rules, &self.anchor, true),
+            is_redact,
+        );
+        diagnostics.push(
+            "ANCHORS",
+            self.ctl.show(ShowModifier::Anchors, "", true),
+            is_redact,
+        );
+        diagnostics.push("INTERFACES", get_interfaces(), is_redact);
+        diagnostics.push("ROUTING", get_routing_table(), is_redact);
+        diagnostics
+    }
+
+    // states from and to `address` (host or network), independent of the lock state
+    pub fn kill_states(&self, address: &str) -> NetlockResult<usize> {
+        let address = normalize_destination(address)?;
+        let any = if address.contains(':') {
+            "::/0"
+        } else {
+            "0.0.0.0/0"
+        };
+        Ok(self.ctl.kill_states(&[&address])? + self.ctl.kill_states(&[any, &address])?)
+    }
+
+    // anchors created through `$` substitution, stale ones are left behind by anchor changes
+    pub fn list_anchors(&self) -> NetlockResult<Vec<String>> {
+        Ok(self
+            .ctl
+            .show(ShowModifier::Anchors, "", true)?
+            .split_whitespace()
+            .filter(|s| s.contains(Self::ANCHOR_REPLACE_TO))
+            .map(|s| s.into())
+            .collect())
+    }
+
     pub fn state(&self) -> bool {
         self.state
     }
@@ -288,14 +1094,24 @@ impl<'a> Manager {
         &mut self.rules
     }
 
-    pub fn set_skipass_loopback(&mut self) -> ExecResult<()> {
-        let loopback_group = "lo".to_string();
-        if self.anchor.is_empty() {
-            self.rules.skip_interfaces.insert(loopback_group);
-        } else {
-            for interface in self
+    pub fn parsers(&mut self) -> &mut ParserRegistry {
+        &mut self.parsers
+    }
+
+    pub fn ctl(&self) -> &Ctl {
+        &self.ctl
+    }
+
+    pub fn set_skipass_loopback(&mut self) -> NetlockResult<()> {
+        let loopback = &self.loopback;
+        if let LoopbackMode::Pass = self.loopback_mode {
+            self.rules.pass_interfaces.insert(loopback.into());
+        } else if self.anchor.is_empty() {
+            self.rules.skip_interfaces.insert(loopback.clone());
+        } else {
+            for interface in self
                 .ctl
-                .show(ShowModifier::Interfaces(&loopback_group), "", true)?
+                .show(ShowModifier::Interfaces(loopback), "", true)?
                 .lines()
                 .map(|s| s.split_whitespace().collect::<Vec<_>>())
                 .filter(|v| v.len() == 1) // v[1] == "(skip)"
@@ -310,8 +1126,12 @@ impl<'a> Manager {
         Ok(())
     }
 
-    pub fn extend_rules_from_routing_table(&mut self) -> ExecResult<()> {
-        let info = get_useful_routing_table_info()?;
+    pub fn extend_rules_from_routing_table(&mut self) -> NetlockResult<()> {
+        self.extend_rules_from_routing_info(&get_useful_routing_table_info()?);
+        Ok(())
+    }
+
+    fn extend_rules_from_routing_info(&mut self, info: &RoutingInfo) {
         let interface = info.interface();
         if !interface.is_empty() {
             self.rules
@@ -324,63 +1144,321 @@ impl<'a> Manager {
                 .pass_destinations
                 .insert(Direction::new(destination).to_out());
         }
+        let gateway = info.gateway();
+        if self.is_pass_gateway_dns && !gateway.is_empty() {
+            self.rules.dns_gateway = Some(gateway.into());
+        }
         if self.is_log {
             eprintln!(
-                "[routing_table] interface: `{}`, destination: `{}`",
-                interface, destination,
+                "[routing_table] interface: `{}`, destination: `{}`, gateway: `{}`",
+                interface, destination, gateway,
             );
         }
+    }
+
+    pub fn extend_lan_from_interfaces(&mut self) -> NetlockResult<()> {
+        let subnets = get_local_subnets()?;
+        if self.is_log {
+            eprintln!("[interfaces] subnets: `{}`", subnets.join(", "));
+        }
+        if let Some(lan) = &mut self.rules.lan {
+            lan.subnets.extend(subnets);
+        }
+        Ok(())
+    }
+
+    // the cached copy is reused when fetching fails, the table references it as a file
+    pub fn extend_block_from_urls(
+        &mut self,
+        urls: &[impl AsRef<str>],
+        cache_dir: &Path,
+    ) -> NetlockResult<()> {
+        for url in urls.iter().map(|s| s.as_ref()) {
+            let cache_path = cache_dir.join(format!(
+                "{}{}.txt",
+                gvars::BLOCKLIST_CACHE_PREFIX,
+                url.chars()
+                    .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+                    .collect::<String>(),
+            ));
+            match fetch_blocklist(url) {
+                Ok(addresses) => {
+                    if self.is_log {
+                        eprintln!(
+                            "[blocklist] url: `{}`, addresses: `{}`",
+                            url,
+                            addresses.len(),
+                        );
+                    }
+                    create_dir_all(cache_dir)?;
+                    write_atomic(&cache_path, addresses.join("\n") + "\n")?;
+                }
+                Err(err) if cache_path.is_file() => {
+                    if self.is_log {
+                        eprintln!(
+                            "[blocklist] url: `{}`, using cached copy: {}",
+                            url,
+                            err.to_string().trim_end(),
+                        );
+                    }
+                }
+                Err(err) => return Err(err.into()),
+            }
+            self.rules
+                .block_destinations
+                .insert(cache_path.display().to_string());
+        }
+        Ok(())
+    }
+
+    pub fn extend_self_from_interfaces(&mut self) -> NetlockResult<()> {
+        let addresses = get_local_addresses()?;
+        if self.is_log {
+            eprintln!("[interfaces] addresses: `{}`", addresses.join(", "));
+        }
+        self.rules.self_addresses.extend(addresses);
         Ok(())
     }
 
     pub fn extend_rules_from_configuration_files(
         &mut self,
         paths: &[impl AsRef<Path>],
-    ) -> io::Result<()> {
-        for destination in &get_destinations_from_configuration_files(paths)? {
-            if self.is_log {
-                eprintln!("[configuration_files] destination: `{}`", destination);
+    ) -> NetlockResult<ConfigurationSummary> {
+        let mut destinations_added = 0;
+        let files =
+            get_destinations_from_configuration_files(paths, &self.parsers, self.is_scan_recursive)
+                .map_err(|err| match err.kind() {
+                    ErrorKind::InvalidData => NetlockError::Parse(err.to_string()),
+                    _ => err.into(),
+                })?;
+        for (path, destinations) in &files {
+            let mut profile = match self.profile_mode {
+                ProfileMode::Merged => None,
+                ProfileMode::Anchors => Some(
+                    self.rules
+                        .profile_destinations
+                        .entry(Self::format_profile(path))
+                        .or_default(),
+                ),
+            };
+            for destination in destinations {
+                if self.rules.is_block_ipv6 && destination.host().parse::<Ipv6Addr>().is_ok() {
+                    if self.is_log {
+                        eprintln!(
+                            "[configuration_files] skip ipv6 destination: `{}`",
+                            destination,
+                        );
+                    }
+                    continue;
+                }
+                if self.rules.is_block_ipv4 && destination.host().parse::<Ipv4Addr>().is_ok() {
+                    if self.is_log {
+                        eprintln!(
+                            "[configuration_files] skip ipv4 destination: `{}`",
+                            destination,
+                        );
+                    }
+                    continue;
+                }
+                if self.is_log {
+                    eprintln!("[configuration_files] destination: `{}`", destination);
+                }
+                let is_added = match profile {
+                    Some(ref mut profile) => profile.insert(destination.host().into()),
+                    None if destination.port().is_some() => {
+                        self.rules.pass_remotes.insert(destination.clone())
+                    }
+                    None => self
+                        .rules
+                        .pass_destinations
+                        .insert(Direction::new(destination.host()).to_out()),
+                };
+                if is_added {
+                    destinations_added += 1;
+                }
             }
-            self.rules
-                .pass_destinations
-                .insert(Direction::new(destination).to_out());
         }
+        Ok(ConfigurationSummary {
+            files,
+            destinations_added,
+        })
+    }
+
+    // compares what pfctl makes of the built rules with the loaded ones, empty if equal
+    pub fn check_drift(&self) -> NetlockResult<Vec<String>> {
+        let (live, built) = self.get_live_and_built_rules()?;
+        let live = live.iter().map(|s| s.as_str()).collect::<Vec<_>>();
+        let built = built.iter().map(|s| s.as_str()).collect::<Vec<_>>();
+        let diff = diff_lines(&live, &built);
+        if diff.iter().all(|s| s.starts_with(' ')) {
+            return Ok(vec![]);
+        }
+        Ok(diff)
+    }
+
+    // `diff -u` of the live rules against the built ones, empty if equal
+    pub fn diff_rules(&self) -> NetlockResult<Vec<String>> {
+        let (live, built) = self.get_live_and_built_rules()?;
+        let live = live.iter().map(|s| s.as_str()).collect::<Vec<_>>();
+        let built = built.iter().map(|s| s.as_str()).collect::<Vec<_>>();
+        Ok(unified_diff(
+            &live,
+            &built,
+            Self::DIFF_LIVE_NAME,
+            Self::DIFF_BUILT_NAME,
+        ))
+    }
+
+    // rule lines as pfctl shows them, comments (the header timestamp), tables, options and
+    // macros of the built rules are dropped
+    fn get_live_and_built_rules(&self) -> NetlockResult<(Vec<String>, Vec<String>)> {
+        let get_rules = |s: &str| {
+            s.lines()
+                .map(|s| s.trim())
+                .filter(|s| Self::is_rule_line(s))
+                .map(|s| s.to_string())
+                .collect::<Vec<_>>()
+        };
+        let live = self.ctl.show(ShowModifier::Rules, &self.anchor, false)?;
+        let built = self.ctl.parse(&self.rules.build()?, &self.anchor)?;
+        Ok((get_rules(&live), get_rules(&built)))
+    }
+
+    pub fn is_dry_run(&self) -> bool {
+        self.ctl.is_dry_run
+    }
+
+    // pfctl commands changing state are logged instead of executed, nothing is written
+    pub fn set_dry_run(&mut self, is_dry_run: bool) {
+        self.ctl.is_dry_run = is_dry_run;
+    }
+
+    pub fn flush_table(&self, table: impl AsRef<str>) -> NetlockResult<()> {
+        Ok(self.ctl.flush_table(table.as_ref(), &self.anchor)?)
+    }
+
+    // live table only, `rules` is left as is and states are kept
+    pub fn add_block_destinations<S: AsRef<str>>(&self, destinations: &[S]) -> NetlockResult<()> {
+        self.ctl.exec_table(
+            &self.rules.block_table_name,
+            TableCommand::Add,
+            destinations,
+            &self.anchor,
+        )?;
+        Ok(())
+    }
+
+    pub fn remove_block_destinations<S: AsRef<str>>(
+        &self,
+        destinations: &[S],
+    ) -> NetlockResult<()> {
+        self.ctl.exec_table(
+            &self.rules.block_table_name,
+            TableCommand::Delete,
+            destinations,
+            &self.anchor,
+        )?;
         Ok(())
     }
 
-    fn load(&mut self, file: LoadFile, new_anchor: Option<impl AsRef<str>>) -> ExecResult<()> {
+    fn format_profile(path: &Path) -> String {
+        let stem = path
+            .file_stem()
+            .map(|s| s.to_string_lossy())
+            .unwrap_or_default();
+        let name = stem
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect::<String>();
+        format!("{}{}", Self::PROFILE_PREFIX, name)
+    }
+
+    fn load(&mut self, file: LoadFile, new_anchor: Option<impl AsRef<str>>) -> NetlockResult<()> {
+        if self.is_validate {
+            let anchor = match &new_anchor {
+                Some(new_anchor) => self.format_anchor(new_anchor.as_ref()),
+                None => self.anchor.clone(),
+            };
+            self.ctl.validate(&file, &anchor)?;
+        }
         self.enable_firewall()?;
         match new_anchor {
             Some(new_anchor) => {
                 let anchor = self.anchor.clone();
                 let new_anchor = self.format_anchor(new_anchor.as_ref());
-                self.ctl.load(file, &new_anchor)?;
+                let warnings = self.ctl.load(file, &new_anchor)?;
+                self.log_warnings(&warnings);
                 if self.state && anchor != new_anchor {
                     self.reset(&anchor)?;
                 }
                 self.anchor = new_anchor;
             }
-            None => self.ctl.load(file, &self.anchor)?,
+            None => {
+                let warnings = self.ctl.load(file, &self.anchor)?;
+                self.log_warnings(&warnings);
+            }
         }
         self.state = true;
+        if !self.ctl.is_dry_run {
+            let ruleset = self.ctl.show(ShowModifier::Rules, &self.anchor, false)?;
+            self.ruleset_hash = Some(Self::hash_ruleset(&ruleset));
+        }
         self.ctl.flush(FlushModifier::States, "")?;
         Ok(())
     }
 
+    fn is_rule_line(s: &str) -> bool {
+        !s.is_empty()
+            && !s.starts_with('#')
+            && !s.starts_with("table ")
+            && !s.starts_with("set ")
+            && !s.contains(" = ")
+    }
+
+    // rule lines only, as shown by pfctl, so it is comparable with a later `-s rules`
+    fn hash_ruleset(ruleset: &str) -> u64 {
+        let rules = ruleset
+            .lines()
+            .map(|s| s.trim())
+            .filter(|s| Self::is_rule_line(s))
+            .collect::<Vec<_>>();
+        fnv1a_64(rules.join("\n").as_bytes())
+    }
+
     fn format_anchor(&self, anchor: &str) -> String {
         anchor.replace(Self::ANCHOR_REPLACE_FROM, Self::ANCHOR_REPLACE_TO)
     }
 
-    fn reset(&self, anchor: &str) -> ExecResult<()> {
+    fn log_warnings(&self, warnings: &[String]) {
+        if self.is_log {
+            for warning in warnings {
+                eprintln!("[pfctl] warning: `{}`", warning);
+            }
+        }
+    }
+
+    fn reset(&self, anchor: &str) -> NetlockResult<()> {
         if anchor.is_empty() {
-            self.ctl.load(LoadFile::Path(&self.ctl.conf_path), "")
+            // the backup only holds filter rules, nat, options and tables come from `pf.conf`
+            let warnings = self.ctl.load(LoadFile::Path(&self.ctl.conf_path), "")?;
+            self.log_warnings(&warnings);
+            if let Some(path) = self.restore_conf_path.as_ref().filter(|p| p.is_file()) {
+                self.ctl.load_filter_rules(&read_to_string(path)?)?;
+            }
+            Ok(())
         } else {
-            self.ctl.flush(FlushModifier::All, anchor)
+            Ok(self.ctl.flush(FlushModifier::All, anchor)?)
         }
     }
 
     #[cfg(not(target_os = "macos"))]
-    fn enable_firewall(&mut self) -> ExecResult<()> {
+    fn enable_firewall(&mut self) -> NetlockResult<()> {
         if !self.ctl.is_enabled()? {
             self.ctl.enable()?;
         }
@@ -388,7 +1466,7 @@ impl<'a> Manager {
     }
 
     #[cfg(target_os = "macos")]
-    fn enable_firewall(&mut self) -> ExecResult<()> {
+    fn enable_firewall(&mut self) -> NetlockResult<()> {
         if !self.ctl.check_token()? {
             self.ctl.enable()?;
         }
@@ -396,7 +1474,7 @@ impl<'a> Manager {
     }
 
     #[cfg(not(target_os = "macos"))]
-    fn disable_firewall(&mut self) -> ExecResult<()> {
+    fn disable_firewall(&mut self) -> NetlockResult<()> {
         if self.ctl.state && self.ctl.is_enabled()? {
             self.ctl.disable()?;
         }
@@ -404,7 +1482,7 @@ impl<'a> Manager {
     }
 
     #[cfg(target_os = "macos")]
-    fn disable_firewall(&mut self) -> ExecResult<()> {
+    fn disable_firewall(&mut self) -> NetlockResult<()> {
         if self.ctl.check_token()? {
             self.ctl.disable()?;
         }
@@ -418,6 +1496,8 @@ impl Default for Manager {
     }
 }
 
+#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
 enum ShowModifier<'a> {
     Rules,
     Anchors,
@@ -455,6 +1535,37 @@ impl Display for ShowModifier<'_> {
     }
 }
 
+// identical `pfctl -s` queries within one status run reuse the first output
+struct ShowCache<'a> {
+    ctl: &'a Ctl,
+    outputs: HashMap<(ShowModifier<'a>, String, bool), String>,
+}
+
+impl<'a> ShowCache<'a> {
+    fn new(ctl: &'a Ctl) -> Self {
+        Self {
+            ctl,
+            outputs: HashMap::new(),
+        }
+    }
+
+    fn show(
+        &mut self,
+        modifier: ShowModifier<'a>,
+        anchor: &str,
+        verbose: bool,
+    ) -> ExecResult<String> {
+        let key = (modifier, anchor.to_string(), verbose);
+        if let Some(output) = self.outputs.get(&key) {
+            return Ok(output.clone());
+        }
+        let output = self.ctl.show(modifier, anchor, verbose)?;
+        self.outputs.insert(key, output.clone());
+        Ok(output)
+    }
+}
+
+#[allow(dead_code)]
 enum FlushModifier {
     Rules,
     States,
@@ -485,36 +1596,40 @@ enum LoadFile<'a> {
     Stdin(&'a str),
 }
 
-// enum TableCommand {
-//     Flush,
-//     Add,
-//     Delete,
-//     Replace,
-//     Show,
-//     Test,
-// }
-//
-// impl<'a> TableCommand {
-//     const FLUSH: &'a str = "flush";
-//     const ADD: &'a str = "add";
-//     const DELETE: &'a str = "delete";
-//     const REPLACE: &'a str = "replace";
-//     const SHOW: &'a str = "show";
-//     const TEST: &'a str = "test";
-// }
-//
-// impl Display for TableCommand {
-//     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-//         match self {
-//             Self::Flush => write!(f, "{}", Self::FLUSH),
-//             Self::Add => write!(f, "{}", Self::ADD),
-//             Self::Delete => write!(f, "{}", Self::DELETE),
-//             Self::Replace => write!(f, "{}", Self::REPLACE),
-//             Self::Show => write!(f, "{}", Self::SHOW),
-//             Self::Test => write!(f, "{}", Self::TEST),
-//         }
-//     }
-// }
+#[allow(dead_code)]
+enum TableCommand {
+    Flush,
+    Add,
+    Delete,
+    Replace,
+    Show,
+    Test,
+    Kill,
+}
+
+impl<'a> TableCommand {
+    const FLUSH: &'a str = "flush";
+    const ADD: &'a str = "add";
+    const DELETE: &'a str = "delete";
+    const REPLACE: &'a str = "replace";
+    const SHOW: &'a str = "show";
+    const TEST: &'a str = "test";
+    const KILL: &'a str = "kill";
+}
+
+impl Display for TableCommand {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Flush => write!(f, "{}", Self::FLUSH),
+            Self::Add => write!(f, "{}", Self::ADD),
+            Self::Delete => write!(f, "{}", Self::DELETE),
+            Self::Replace => write!(f, "{}", Self::REPLACE),
+            Self::Show => write!(f, "{}", Self::SHOW),
+            Self::Test => write!(f, "{}", Self::TEST),
+            Self::Kill => write!(f, "{}", Self::KILL),
+        }
+    }
+}
 
 pub struct Ctl {
     ctl_path: PathBuf,
@@ -523,11 +1638,15 @@ pub struct Ctl {
     state: bool,
     #[cfg(target_os = "macos")]
     token: String,
+    is_dry_run: bool,
+    timeout: Duration,
 }
 
 impl<'a> Ctl {
     pub const DEFAULT_CTL_PATH: &'a str = "/sbin/pfctl";
     pub const DEFAULT_CONF_PATH: &'a str = "/etc/pf.conf";
+    // pfctl can wedge, e.g. on a bad interface
+    pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
 
     #[cfg(not(target_os = "macos"))]
     const FLAG_ENABLE: &'a str = "-e";
@@ -543,26 +1662,73 @@ impl<'a> Ctl {
     const FLAG_FILE: &'a str = "-f";
     const FLAG_VERBOSE: &'a str = "-v";
     const FLAG_INTERFACE: &'a str = "-i";
-    // const FLAG_TABLE: &'a str = "-t";
-    // const FLAG_TABLE_COMMAND: &'a str = "-T";
+    const FLAG_NO_ACTION: &'a str = "-n";
+    const FLAG_FILTER_ONLY: &'a str = "-R";
+    const FLAG_TABLE: &'a str = "-t";
+    const FLAG_KILL_STATES: &'a str = "-k";
+    const FLAG_TABLE_COMMAND: &'a str = "-T";
 
     pub fn new<P: Into<PathBuf>>(ctl_path: P, conf_path: P) -> Self {
+        Self::try_new(ctl_path, conf_path).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    pub fn try_new<P: Into<PathBuf>>(ctl_path: P, conf_path: P) -> io::Result<Self> {
         let ctl_path = ctl_path.into();
-        assert!(ctl_path.is_executable());
+        if !ctl_path.is_executable() {
+            return Err(io::Error::new(
+                ErrorKind::NotFound,
+                format!(
+                    "pfctl not found or not executable: `{}`",
+                    ctl_path.display()
+                ),
+            ));
+        }
         let conf_path = conf_path.into().expanduser();
-        assert!(conf_path.is_file() && !conf_path.starts_with("~"));
-        Self {
+        if !conf_path.is_file() || conf_path.starts_with("~") {
+            return Err(io::Error::new(
+                ErrorKind::NotFound,
+                format!("pf conf not found: `{}`", conf_path.display()),
+            ));
+        }
+        Ok(Self {
             ctl_path,
             conf_path,
             #[cfg(not(target_os = "macos"))]
             state: false,
             #[cfg(target_os = "macos")]
             token: "".into(),
-        }
+            is_dry_run: false,
+            timeout: Self::DEFAULT_TIMEOUT,
+        })
+    }
+
+    // `Default` panics if pfctl is missing
+    pub fn try_default() -> io::Result<Self> {
+        Self::try_new(Self::DEFAULT_CTL_PATH, Self::DEFAULT_CONF_PATH)
+    }
+
+    pub fn ctl_path(&self) -> &Path {
+        &self.ctl_path
+    }
+
+    // `pf.conf` reloaded on anchorless disable, expanded
+    pub fn conf_path(&self) -> &Path {
+        &self.conf_path
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
     }
 
     #[cfg(not(target_os = "macos"))]
     fn enable(&mut self) -> ExecResult<()> {
+        if self.log_dry_run(&[Self::FLAG_ENABLE]) {
+            return Ok(());
+        }
         self.exec(&[Self::FLAG_ENABLE])?;
         self.state = true;
         Ok(())
@@ -570,6 +1736,9 @@ impl<'a> Ctl {
 
     #[cfg(target_os = "macos")]
     fn enable(&mut self) -> ExecResult<()> {
+        if self.log_dry_run(&[Self::FLAG_ENABLE]) {
+            return Ok(());
+        }
         let mut token = String::new();
         for opt in String::from_utf8_lossy(&self.exec(&[Self::FLAG_ENABLE])?.stderr)
             .to_lowercase()
@@ -589,6 +1758,9 @@ impl<'a> Ctl {
 
     #[cfg(not(target_os = "macos"))]
     fn disable(&mut self) -> ExecResult<()> {
+        if self.log_dry_run(&[Self::FLAG_DISABLE]) {
+            return Ok(());
+        }
         self.exec(&[Self::FLAG_DISABLE])?;
         self.state = false;
         Ok(())
@@ -597,6 +1769,9 @@ impl<'a> Ctl {
     #[cfg(target_os = "macos")]
     fn disable(&mut self) -> ExecResult<bool> {
         assert!(!self.token.is_empty());
+        if self.log_dry_run(&[Self::FLAG_DISABLE, &self.token]) {
+            return Ok(true);
+        }
         let is_disabled =
             String::from_utf8_lossy(&self.exec(&[Self::FLAG_DISABLE, &self.token])?.stderr)
                 .to_lowercase()
@@ -628,30 +1803,100 @@ impl<'a> Ctl {
         if !anchor.is_empty() {
             args.extend_from_slice(&[Self::FLAG_ANCHOR, anchor]);
         }
-        self.exec(&args)?;
+        if !self.log_dry_run(&args) {
+            self.exec(&args)?;
+        }
         Ok(())
     }
 
-    fn load(&self, file: LoadFile, anchor: &str) -> ExecResult<()> {
-        match file {
+    fn flush_table(&self, table: &str, anchor: &str) -> ExecResult<()> {
+        self.exec_table::<&str>(table, TableCommand::Flush, &[], anchor)?;
+        Ok(())
+    }
+
+    // pfctl may succeed and still print warnings, these are returned line by line
+    fn load(&self, file: LoadFile, anchor: &str) -> ExecResult<Vec<String>> {
+        let output = match file {
             LoadFile::Path(path) => {
                 let mut args = vec![OsStr::new(Self::FLAG_FILE), path.as_os_str()];
                 if !anchor.is_empty() {
                     args.extend_from_slice(&[OsStr::new(Self::FLAG_ANCHOR), OsStr::new(anchor)]);
                 }
-                self.exec(&args)?;
+                if self.log_dry_run(&args) {
+                    return Ok(vec![]);
+                }
+                self.exec(&args)?
             }
             LoadFile::Stdin(rules) => {
                 let mut args = vec![Self::FLAG_FILE, "-"];
                 if !anchor.is_empty() {
                     args.extend_from_slice(&[Self::FLAG_ANCHOR, anchor]);
                 }
-                exec_stdin(&self.ctl_path, &args, rules)?;
+                if self.log_dry_run(&args) {
+                    self.parse(rules, anchor)?;
+                    return Ok(vec![]);
+                }
+                exec_stdin_timeout(&self.ctl_path, &args, rules, self.timeout)?
+            }
+        };
+        Ok(String::from_utf8_lossy(&output.stderr)
+            .lines()
+            .map(|s| s.trim())
+            .filter(|s| !s.is_empty())
+            .map(|s| s.into())
+            .collect())
+    }
+
+    // `-R` leaves nat, options and tables of the loaded ruleset alone
+    fn load_filter_rules(&self, rules: &str) -> ExecResult<()> {
+        let args = [Self::FLAG_FILTER_ONLY, Self::FLAG_FILE, "-"];
+        if self.log_dry_run(&args) {
+            return Ok(());
+        }
+        exec_stdin_timeout(&self.ctl_path, args, rules, self.timeout)?;
+        Ok(())
+    }
+
+    fn validate(&self, file: &LoadFile, anchor: &str) -> ExecResult<()> {
+        match file {
+            LoadFile::Path(path) => {
+                let mut args = vec![
+                    OsStr::new(Self::FLAG_NO_ACTION),
+                    OsStr::new(Self::FLAG_FILE),
+                    path.as_os_str(),
+                ];
+                if !anchor.is_empty() {
+                    args.extend_from_slice(&[OsStr::new(Self::FLAG_ANCHOR), OsStr::new(anchor)]);
+                }
+                self.exec(&args)?;
+            }
+            LoadFile::Stdin(rules) => {
+                let mut args = vec![Self::FLAG_NO_ACTION, Self::FLAG_FILE, "-"];
+                if !anchor.is_empty() {
+                    args.extend_from_slice(&[Self::FLAG_ANCHOR, anchor]);
+                }
+                exec_stdin_timeout(&self.ctl_path, &args, rules, self.timeout)?;
             }
         }
         Ok(())
     }
 
+    fn parse(&self, rules: &str, anchor: &str) -> ExecResult<String> {
+        let mut args = vec![
+            Self::FLAG_NO_ACTION,
+            Self::FLAG_VERBOSE,
+            Self::FLAG_FILE,
+            "-",
+        ];
+        if !anchor.is_empty() {
+            args.extend_from_slice(&[Self::FLAG_ANCHOR, anchor]);
+        }
+        Ok(String::from_utf8_lossy(
+            &exec_stdin_timeout(&self.ctl_path, &args, rules, self.timeout)?.stdout,
+        )
+        .into())
+    }
+
     fn show(&self, modifier: ShowModifier, anchor: &str, verbose: bool) -> ExecResult<String> {
         let modifier_ptr = &modifier.to_string();
         let mut args = vec![Self::FLAG_SHOW, modifier_ptr];
@@ -669,31 +1914,71 @@ impl<'a> Ctl {
         Ok(String::from_utf8_lossy(&self.exec(&args)?.stdout).into())
     }
 
-    // fn exec_table<S: AsRef<str>>(
-    //     &self,
-    //     table: &str,
-    //     command: TableCommand,
-    //     addresses: &[S],
-    //     anchor: &str,
-    // ) -> ExecResult<Output> {
-    //     let mut args = vec![Self::FLAG_TABLE, table];
-    //     if !anchor.is_empty() {
-    //         args.extend_from_slice(&[Self::FLAG_ANCHOR, anchor]);
-    //     }
-    //     let command = command.to_string();
-    //     args.extend_from_slice(&[Self::FLAG_TABLE_COMMAND, &command]);
-    //     for address in addresses.iter().map(|s| s.as_ref()) {
-    //         if address.starts_with('/') {
-    //             args.extend_from_slice(&[Self::FLAG_FILE, address]);
-    //         } else {
-    //             args.push(address);
-    //         }
-    //     }
-    //     self.exec(&args)
-    // }
+    // `-k src [-k dst]`, pfctl reports `killed N states from ..` on stderr
+    fn kill_states(&self, hosts: &[&str]) -> ExecResult<usize> {
+        let args = hosts
+            .iter()
+            .flat_map(|s| [Self::FLAG_KILL_STATES, s])
+            .collect::<Vec<_>>();
+        if self.log_dry_run(&args) {
+            return Ok(0);
+        }
+        let output = self.exec(&args)?;
+        Ok(String::from_utf8_lossy(&output.stderr)
+            .lines()
+            .filter_map(|s| s.trim().strip_prefix("killed ")?.split_whitespace().next())
+            .filter_map(|s| s.parse::<usize>().ok())
+            .sum())
+    }
+
+    fn exec_table<S: AsRef<str>>(
+        &self,
+        table: &str,
+        command: TableCommand,
+        addresses: &[S],
+        anchor: &str,
+    ) -> ExecResult<Output> {
+        let mut args = vec![Self::FLAG_TABLE, table];
+        if !anchor.is_empty() {
+            args.extend_from_slice(&[Self::FLAG_ANCHOR, anchor]);
+        }
+        let is_mutating = !matches!(command, TableCommand::Show | TableCommand::Test);
+        let command = command.to_string();
+        args.extend_from_slice(&[Self::FLAG_TABLE_COMMAND, &command]);
+        for address in addresses.iter().map(|s| s.as_ref()) {
+            if address.starts_with('/') {
+                args.extend_from_slice(&[Self::FLAG_FILE, address]);
+            } else {
+                args.push(address);
+            }
+        }
+        if is_mutating && self.log_dry_run(&args) {
+            return Ok(Output {
+                status: Default::default(),
+                stdout: vec![],
+                stderr: vec![],
+            });
+        }
+        self.exec(&args)
+    }
+
+    // mutating commands are only logged in dry run
+    fn log_dry_run<S: AsRef<OsStr>>(&self, args: &[S]) -> bool {
+        if self.is_dry_run {
+            eprintln!(
+                "[dry_run] {} {}",
+                self.ctl_path.display(),
+                args.iter()
+                    .map(|s| s.as_ref().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            );
+        }
+        self.is_dry_run
+    }
 
     fn exec<S: AsRef<OsStr>>(&self, args: &[S]) -> ExecResult<Output> {
-        exec(&self.ctl_path, args)
+        exec_timeout(&self.ctl_path, args, self.timeout)
     }
 }
 
@@ -703,7 +1988,10 @@ impl Default for Ctl {
     }
 }
 
+#[derive(Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum BlockPolicy {
+    #[default]
     Drop,
     Return,
 }
@@ -722,14 +2010,80 @@ impl Display for BlockPolicy {
     }
 }
 
-impl Default for BlockPolicy {
+// `# <unix-time>` line of the header, fixed or omitted makes `build` reproducible
+#[derive(Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Timestamp {
+    #[default]
+    Now,
+    At(u64),
+    Omit,
+}
+
+// pf dialect the rules are written for, sections that vary:
+// SCRUB: openbsd dropped `scrub`, normalization is `match .. scrub (..)`
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Platform {
+    Macos,
+    FreeBsd,
+    OpenBsd,
+}
+
+impl<'a> Platform {
+    pub const MACOS: &'a str = "macos";
+    pub const FREEBSD: &'a str = "freebsd";
+    pub const OPENBSD: &'a str = "openbsd";
+
+    // the running system, other unixes get the macos (old pf) syntax
+    pub fn detect() -> Self {
+        if cfg!(target_os = "openbsd") {
+            Self::OpenBsd
+        } else if cfg!(target_os = "freebsd") {
+            Self::FreeBsd
+        } else {
+            Self::Macos
+        }
+    }
+}
+
+impl Default for Platform {
     fn default() -> Self {
-        Self::Drop
+        Self::detect()
+    }
+}
+
+impl FromStr for Platform {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            Self::MACOS => Ok(Self::Macos),
+            Self::FREEBSD => Ok(Self::FreeBsd),
+            Self::OPENBSD => Ok(Self::OpenBsd),
+            _ => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid platform: `{}`", s),
+            )),
+        }
+    }
+}
+
+impl Display for Platform {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Macos => write!(f, "{}", Self::MACOS),
+            Self::FreeBsd => write!(f, "{}", Self::FREEBSD),
+            Self::OpenBsd => write!(f, "{}", Self::OPENBSD),
+        }
     }
 }
 
+#[derive(Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum StatePolicy {
     IfBound,
+    #[default]
     Floating,
 }
 
@@ -747,26 +2101,45 @@ impl Display for StatePolicy {
     }
 }
 
-impl Default for StatePolicy {
-    fn default() -> Self {
-        Self::Floating
+// without state replies are not matched automatically, the reverse direction needs its own pass
+#[derive(Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum PassState {
+    #[default]
+    Keep,
+    No,
+}
+
+impl<'a> PassState {
+    const KEEP: &'a str = "keep state";
+    const NO: &'a str = "no state";
+}
+
+impl Display for PassState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Keep => write!(f, "{}", Self::KEEP),
+            Self::No => write!(f, "{}", Self::NO),
+        }
     }
 }
 
+#[derive(Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Action {
+    #[default]
     Block,
     Pass,
 }
 
-impl Default for Action {
-    fn default() -> Self {
-        Self::Block
-    }
-}
-
+#[derive(Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Antispoofing {
     NoRoute,
+    #[default]
     UrpfFailed,
+    // `antispoof for` the interfaces, empty means the pass interfaces
+    Interface(Vec<String>),
 }
 
 impl<'a> Antispoofing {
@@ -779,77 +2152,480 @@ impl Display for Antispoofing {
         match self {
             Self::NoRoute => write!(f, "{}", Self::NO_ROUTE),
             Self::UrpfFailed => write!(f, "{}", Self::URPF_FAILED),
+            Self::Interface(interfaces) => write!(f, "{{ {} }}", interfaces.join(" ")),
         }
     }
 }
 
-impl Default for Antispoofing {
-    fn default() -> Self {
-        Self::UrpfFailed
-    }
+#[derive(Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum BlockIpv6 {
+    #[default]
+    All,
+    Routable,
 }
 
+#[derive(Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Multicast {
+    #[default]
     NotRoutable,
     All,
 }
 
-impl Default for Multicast {
-    fn default() -> Self {
-        Self::NotRoutable
-    }
-}
-
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Lan {
     pub is_block_out_dns: bool,
+    // mdns and ssdp, for printer and cast discovery
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub is_pass_service_discovery: bool,
     pub multicast: Multicast,
+    pub subnets: HashSet<String>,
+}
+
+impl Lan {
+    fn get_networks(&self) -> (Vec<&str>, Vec<&str>) {
+        if self.subnets.is_empty() {
+            return (
+                gvars::IPV4_PRIVATE_NETWORKS.to_vec(),
+                gvars::IPV6_PRIVATE_NETWORKS.to_vec(),
+            );
+        }
+        let mut subnets = self.subnets.iter().map(|s| s.as_str()).collect::<Vec<_>>();
+        subnets.sort_unstable();
+        subnets.into_iter().partition(|s| !s.contains(':'))
+    }
 }
 
 impl Default for Lan {
     fn default() -> Self {
         Self {
             is_block_out_dns: true,
+            is_pass_service_discovery: false,
             multicast: Default::default(),
+            subnets: Default::default(),
         }
     }
 }
 
-pub enum ICMP {
-    Echoreq,
-    All,
+// allowed type names per family (`unreach`, `neighbrsol`, ..), empty passes all types
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ICMP {
+    pub types: Vec<String>,
+    pub types6: Vec<String>,
+}
+
+impl ICMP {
+    const ECHOREQ: &str = "echoreq";
+
+    pub fn echoreq() -> Self {
+        Self {
+            types: vec![Self::ECHOREQ.into()],
+            types6: vec![Self::ECHOREQ.into()],
+        }
+    }
+
+    pub fn all() -> Self {
+        Self {
+            types: vec![],
+            types6: vec![],
+        }
+    }
 }
 
 impl Default for ICMP {
     fn default() -> Self {
-        Self::Echoreq
+        Self::echoreq()
     }
 }
 
-pub struct Rules {
-    block_table_name: String,
-    in_table_name: String,
-    out_table_name: String,
-    pub block_policy: BlockPolicy,
-    pub state_policy: StatePolicy,
-    pub min_ttl: u8,
-    pub is_enable_log: bool,
-    pub incoming: Action,
-    pub outgoing: Action,
-    pub antispoofing: Option<Antispoofing>,
-    pub is_block_ipv6: bool,
-    pub lan: Option<Lan>,
-    pub icmp: Option<ICMP>,
-    pub skip_interfaces: HashSet<String>,
-    pub pass_interfaces: HashSet<Direction>,
-    pub pass_owners: HashSet<Owner>,
-    pub block_destinations: HashSet<String>,
-    pub pass_destinations: HashSet<Direction>,
+#[derive(PartialEq, Eq, Clone, Copy, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LogSections(u8);
+
+impl LogSections {
+    pub const NONE: Self = Self(0);
+    pub const INCOMING: Self = Self(1);
+    pub const OUTGOING: Self = Self(1 << 1);
+    pub const ANTISPOOFING: Self = Self(1 << 2);
+    pub const ALL: Self = Self(Self::INCOMING.0 | Self::OUTGOING.0 | Self::ANTISPOOFING.0);
+
+    pub fn contains(&self, other: Self) -> bool {
+        other.0 != 0 && self.0 & other.0 == other.0
+    }
 }
 
-impl<'a> Rules {
-    pub const DEFAULT_BLOCK_TABLE_NAME: &'a str = "netlock_block";
-    pub const DEFAULT_IN_TABLE_NAME: &'a str = "netlock_pass_in";
-    pub const DEFAULT_OUT_TABLE_NAME: &'a str = "netlock_pass_out";
+impl BitOr for LogSections {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+// pf `tos` matches the whole byte, dscp is its upper six bits (ef 46 -> 0xb8)
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TosRule {
+    pub tos: u8,
+    pub proto: Option<String>,
+    pub interface: Option<String>,
+}
+
+impl TosRule {
+    pub const DSCP_EF: u8 = 46;
+
+    pub fn new(tos: u8) -> Self {
+        Self {
+            tos,
+            proto: None,
+            interface: None,
+        }
+    }
+
+    pub fn from_dscp(dscp: u8) -> Self {
+        Self::new(dscp << 2)
+    }
+}
+
+// `udp/123`, `tcp/443` or a bare `123` for both
+#[derive(PartialEq, Eq, Hash, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PortSpec {
+    pub proto: Option<String>,
+    pub port: u16,
+}
+
+impl FromStr for PortSpec {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (proto, port) = match s.split_once('/') {
+            Some((proto, port)) => (Some(proto), port),
+            None => (None, s),
+        };
+        match (proto, port.parse::<u16>()) {
+            (Some(Remote::TCP), Ok(port)) | (Some(Remote::UDP), Ok(port)) | (None, Ok(port))
+                if port != 0 =>
+            {
+                Ok(Self {
+                    proto: proto.map(|s| s.into()),
+                    port,
+                })
+            }
+            _ => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid port: `{}`", s),
+            )),
+        }
+    }
+}
+
+impl Display for PortSpec {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.proto {
+            Some(proto) => write!(f, "{}/{}", proto, self.port),
+            None => write!(f, "{}", self.port),
+        }
+    }
+}
+
+#[derive(Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MatchRule {
+    pub interface: Option<Direction>,
+    pub destination: Option<String>,
+    pub prio: Option<u8>,
+    pub queue: Option<String>,
+}
+
+impl MatchRule {
+    pub const MAX_PRIO: u8 = 7;
+}
+
+impl Display for MatchRule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "match")?;
+        if let Some(interface) = &self.interface {
+            if interface.is_in() {
+                write!(f, " in")?;
+            } else if interface.is_out() {
+                write!(f, " out")?;
+            }
+            write!(f, " on {}", interface.safe_unwrap())?;
+        }
+        match &self.destination {
+            Some(destination) => write!(f, " from any to {}", destination)?,
+            None => write!(f, " all")?,
+        }
+        if let Some(prio) = self.prio {
+            write!(f, " set prio {}", prio)?;
+        }
+        if let Some(queue) = &self.queue {
+            write!(f, " set queue {}", queue)?;
+        }
+        Ok(())
+    }
+}
+
+// options of the `scrub in all` line, default is the bare line
+#[derive(Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Scrub {
+    pub is_no_df: bool,
+    pub is_random_id: bool,
+    pub is_reassemble_tcp: bool,
+    pub max_mss: Option<u16>,
+}
+
+impl Scrub {
+    // `scrub (..)` options of an openbsd match rule, reassembly is always on there
+    fn get_match_options(&self) -> Vec<String> {
+        let mut options = vec![];
+        if self.is_no_df {
+            options.push("no-df".into());
+        }
+        if self.is_random_id {
+            options.push("random-id".into());
+        }
+        if self.is_reassemble_tcp {
+            options.push("reassemble tcp".into());
+        }
+        if let Some(max_mss) = self.max_mss {
+            options.push(format!("max-mss {}", max_mss));
+        }
+        options
+    }
+
+    fn from_options(options: &[&str]) -> Option<Self> {
+        let mut scrub = Self::default();
+        let mut options = options.iter();
+        while let Some(&option) = options.next() {
+            match option {
+                "no-df" => scrub.is_no_df = true,
+                "random-id" => scrub.is_random_id = true,
+                "reassemble" if options.next() == Some(&"tcp") => scrub.is_reassemble_tcp = true,
+                "max-mss" => scrub.max_mss = Some(options.next()?.parse().ok()?),
+                _ => return None,
+            }
+        }
+        Some(scrub)
+    }
+
+    fn from_match_options(options: &[&str]) -> Option<Self> {
+        let options = options.join(" ");
+        let options = options.strip_prefix('(')?.strip_suffix(')')?;
+        Self::from_options(&options.split_whitespace().collect::<Vec<_>>())
+    }
+}
+
+impl Display for Scrub {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "scrub in all")?;
+        if self.is_no_df {
+            write!(f, " no-df")?;
+        }
+        if self.is_random_id {
+            write!(f, " random-id")?;
+        }
+        if self.is_reassemble_tcp {
+            write!(f, " reassemble tcp")?;
+        }
+        if let Some(max_mss) = self.max_mss {
+            write!(f, " max-mss {}", max_mss)?;
+        }
+        Ok(())
+    }
+}
+
+// connection limits on the pass in rule, sources over the limits land in the overload table
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct InboundProtection {
+    pub max_src_conn: u32,
+    pub max_src_conn_rate: Option<(u32, u32)>,
+    pub overload_table_name: String,
+    pub is_flush_global: bool,
+}
+
+impl InboundProtection {
+    pub const DEFAULT_OVERLOAD_TABLE_NAME: &str = "netlock_abusers";
+
+    fn from_options(s: &str) -> Option<Self> {
+        let mut protection = Self {
+            max_src_conn: 0,
+            max_src_conn_rate: None,
+            overload_table_name: Self::DEFAULT_OVERLOAD_TABLE_NAME.into(),
+            is_flush_global: false,
+        };
+        let options = s.split_once('(')?.1.split_once(')')?.0;
+        for option in options.split(',') {
+            match option.split_whitespace().collect::<Vec<_>>()[..] {
+                ["max-src-conn", n] => protection.max_src_conn = n.parse().ok()?,
+                ["max-src-conn-rate", rate] => {
+                    let (n, seconds) = rate.split_once('/')?;
+                    protection.max_src_conn_rate = Some((n.parse().ok()?, seconds.parse().ok()?));
+                }
+                ["overload", name, ref flush @ ..] => {
+                    protection.overload_table_name =
+                        name.trim_start_matches('<').trim_end_matches('>').into();
+                    protection.is_flush_global = match flush {
+                        [] => false,
+                        ["flush", "global"] => true,
+                        _ => return None,
+                    };
+                }
+                _ => return None,
+            }
+        }
+        Some(protection)
+    }
+}
+
+impl Default for InboundProtection {
+    fn default() -> Self {
+        Self {
+            max_src_conn: 100,
+            max_src_conn_rate: Some((15, 5)),
+            overload_table_name: Self::DEFAULT_OVERLOAD_TABLE_NAME.into(),
+            is_flush_global: true,
+        }
+    }
+}
+
+impl Display for InboundProtection {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "keep state (max-src-conn {}", self.max_src_conn)?;
+        if let Some((n, seconds)) = self.max_src_conn_rate {
+            write!(f, ", max-src-conn-rate {}/{}", n, seconds)?;
+        }
+        write!(f, ", overload <{}>", self.overload_table_name)?;
+        if self.is_flush_global {
+            write!(f, " flush global")?;
+        }
+        write!(f, ")")
+    }
+}
+
+type RulesWriter = fn(&Rules, &mut dyn IoWrite) -> IoResult<()>;
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct Rules {
+    block_table_name: String,
+    in_table_name: String,
+    out_table_name: String,
+    pub platform: Platform,
+    pub timestamp: Timestamp,
+    // `set block-policy` follows the incoming one, outgoing blocks return by default
+    pub block_policy_in: BlockPolicy,
+    pub block_policy_out: BlockPolicy,
+    pub state_policy: StatePolicy,
+    pub limits: HashMap<String, u32>,
+    pub timeouts: HashMap<String, u32>,
+    pub scrub: Scrub,
+    pub min_ttl: u8,
+    pub log: LogSections,
+    // `log (to pflogN)` instead of the default pflog0
+    pub log_device: Option<String>,
+    // interface pf gathers statistics for (`pfctl -s info`)
+    pub loginterface: Option<String>,
+    pub incoming: Action,
+    pub outgoing: Action,
+    pub antispoofing: Option<Antispoofing>,
+    pub is_block_ipv6: bool,
+    pub block_ipv6: BlockIpv6,
+    // for ipv6-only tunnels, together with `is_block_ipv6` nothing passes at all
+    pub is_block_ipv4: bool,
+    pub is_block_bogons: bool,
+    // tables get the addresses resolved at build time, pf resolves hostnames once on load
+    pub is_resolve_hostnames: bool,
+    pub dns_gateway: Option<String>,
+    pub dns_servers: Vec<String>,
+    pub pass_out_ports: Vec<PortSpec>,
+    pub self_addresses: HashSet<String>,
+    #[cfg(target_os = "macos")]
+    pub is_pass_continuity: bool,
+    pub lan: Option<Lan>,
+    pub is_isolate_lan: bool,
+    // also implied by `is_isolate_lan`
+    pub is_pass_dhcp: bool,
+    pub icmp: Option<ICMP>,
+    pub is_pass_icmp_errors: bool,
+    pub skip_interfaces: HashSet<String>,
+    pub pass_interfaces: HashSet<Direction>,
+    pub pass_owners: HashSet<Owner>,
+    pub pass_state: PassState,
+    pub tos_rules: Vec<TosRule>,
+    pub block_destinations: HashSet<String>,
+    pub pass_destinations: HashSet<Direction>,
+    // destinations passed out only on the interface, each gets an `<out_table>_<interface>` table
+    pub interface_destinations: HashMap<String, HashSet<String>>,
+    pub pass_remotes: HashSet<Remote>,
+    pub inbound_protection: Option<InboundProtection>,
+    // (interface gateway) for the out table pass, remotes are tunnel endpoints and are not routed;
+    // out passes on `pass_interfaces` are written first and win, so the physical interface must
+    // not be listed there, with `state-policy if-bound` states are bound to the route-to
+    // interface, which then needs its own out pass (list it in `pass_interfaces`)
+    pub route_to: Option<(String, String)>,
+    pub is_strict_egress: bool,
+    // tunnel egress passes get `tag`, untagged outgoing traffic reaching the default block is
+    // logged by a companion block
+    pub tag: Option<String>,
+    // without `quick` interface, owner and destination passes leave later rules (own anchors)
+    // the last word
+    pub is_quick: bool,
+    pub profile_destinations: HashMap<String, HashSet<String>>,
+    pub match_rules: Vec<MatchRule>,
+    pub extra_rules: Vec<String>,
+}
+
+impl<'a> Rules {
+    pub const DEFAULT_BLOCK_TABLE_NAME: &'a str = "netlock_block";
+    pub const DEFAULT_IN_TABLE_NAME: &'a str = "netlock_pass_in";
+    pub const DEFAULT_OUT_TABLE_NAME: &'a str = "netlock_pass_out";
+    // pf table names are at most 31 characters
+    pub const MAX_TABLE_NAME_LEN: usize = 31;
+    pub const LABELS: [&'a str; 17] = [
+        "ANTISPOOFING",
+        "BLOCKLIST_IN",
+        "BLOCKLIST_OUT",
+        "CONTINUITY",
+        "DHCP",
+        "DHCP6",
+        "ICMP",
+        "ICMP6",
+        "ICMP_ERRORS",
+        "ICMP6_ERRORS",
+        "IPV6_LOCAL",
+        "LAN_ISOLATION",
+        "NDP",
+        "SELF",
+        "SERVICE_DISCOVERY",
+        "TOS",
+        "UNTAGGED",
+    ];
+    pub const LIMITS: [&'a str; 5] = ["states", "frags", "src-nodes", "tables", "table-entries"];
+    pub const TIMEOUTS: [&'a str; 20] = [
+        "tcp.first",
+        "tcp.opening",
+        "tcp.established",
+        "tcp.closing",
+        "tcp.finwait",
+        "tcp.closed",
+        "tcp.tsdiff",
+        "udp.first",
+        "udp.single",
+        "udp.multiple",
+        "icmp.first",
+        "icmp.error",
+        "other.first",
+        "other.single",
+        "other.multiple",
+        "frag",
+        "interval",
+        "src.track",
+        "adaptive.start",
+        "adaptive.end",
+    ];
 
     pub fn new<S: Into<String>>(block_table_name: S, in_table_name: S, out_table_name: S) -> Self {
         Self {
@@ -860,42 +2636,149 @@ impl<'a> Rules {
         }
     }
 
+    pub fn builder() -> RulesBuilder {
+        Default::default()
+    }
+
+    pub fn set_block_policy(&mut self, block_policy: BlockPolicy) {
+        self.block_policy_in = block_policy;
+        self.block_policy_out = block_policy;
+    }
+
+    pub fn block_table_name(&self) -> &str {
+        &self.block_table_name
+    }
+
+    pub fn in_table_name(&self) -> &str {
+        &self.in_table_name
+    }
+
+    pub fn out_table_name(&self) -> &str {
+        &self.out_table_name
+    }
+
+    pub fn set_table_names<S: Into<String>>(
+        &mut self,
+        block_table_name: S,
+        in_table_name: S,
+        out_table_name: S,
+    ) {
+        self.block_table_name = block_table_name.into();
+        self.in_table_name = in_table_name.into();
+        self.out_table_name = out_table_name.into();
+    }
+
     // based on `true story` (Eddie by AirVPN)
-    #[allow(unused_must_use)]
-    pub fn build(&self) -> String {
-        let mut rules = Vec::new();
-        self.write(&mut rules);
-        String::from_utf8(rules).expect("Rules.write() invalid utf-8")
+    pub fn build(&self) -> IoResult<String> {
+        let rules = self
+            .build_sections()?
+            .iter()
+            .map(|(name, section)| format!("# {}\n{}", name, section))
+            .collect::<String>();
+        if self.is_strict_egress {
+            Self::check_egress(&rules)?;
+        }
+        Ok(rules)
+    }
+
+    // nothing passing out beyond lan, icmp and dns locks out the tunnel too
+    pub fn preflight(&self) -> NetlockResult<()> {
+        if let Action::Pass = self.outgoing {
+            return Ok(());
+        }
+        let is_out = |d: &Direction| !d.is_in();
+        if !self.pass_out_ports.is_empty()
+            || !self.pass_remotes.is_empty()
+            || !self.tos_rules.is_empty()
+            || self.pass_interfaces.iter().any(is_out)
+            || self.pass_destinations.iter().any(is_out)
+            || self.pass_owners.iter().any(|o| !o.is_in())
+            || self.skip_interfaces.iter().any(|s| !s.starts_with("lo"))
+            || self.interface_destinations.values().any(|s| !s.is_empty())
+            || self.profile_destinations.values().any(|s| !s.is_empty())
+            || self
+                .extra_rules
+                .iter()
+                .any(|s| s.trim_start().starts_with("pass"))
+        {
+            return Ok(());
+        }
+        Err(NetlockError::Lockout)
+    }
+
+    // (name, section) in build order, the `# NAME` header line is not part of the section
+    pub fn build_sections(&self) -> IoResult<Vec<(String, String)>> {
+        let mut sections = vec![];
+        for write in Self::get_writers() {
+            let mut section = Vec::new();
+            write(self, &mut section)?;
+            let section = String::from_utf8(section).expect("Rules.write() invalid utf-8");
+            let (header, section) = section.split_once('\n').unwrap_or((&section, ""));
+            sections.push((
+                header.trim_start_matches('#').trim().to_string(),
+                section.to_string(),
+            ));
+        }
+        Ok(sections)
     }
 
     pub fn write(&self, mut to: impl IoWrite) -> IoResult<()> {
-        self.write_header(&mut to)?;
-        self.write_options(&mut to)?;
-        self.write_scrub(&mut to)?;
-        self.write_incoming(&mut to)?;
-        self.write_outgoing(&mut to)?;
-        self.write_antispoofing(&mut to)?;
-        self.write_blocklist(&mut to)?;
-        self.write_interfaces(&mut to)?;
-        self.write_owners(&mut to)?;
-        self.write_ipv6(&mut to)?;
-        self.write_lan(&mut to)?;
-        self.write_icmp(&mut to)?;
-        self.write_destinations(&mut to)?;
+        for write in Self::get_writers() {
+            write(self, &mut to)?;
+        }
         Ok(())
     }
 
     pub fn write_header(&self, mut to: impl IoWrite) -> IoResult<()> {
         writeln!(&mut to, "# HEADER")?;
-        writeln!(&mut to, "# {}", &time())?;
+        match self.timestamp {
+            Timestamp::Now => writeln!(&mut to, "# {}", &time())?,
+            Timestamp::At(timestamp) => writeln!(&mut to, "# {}", timestamp)?,
+            Timestamp::Omit => {}
+        }
         writeln!(&mut to)
     }
 
     pub fn write_options(&self, mut to: impl IoWrite) -> IoResult<()> {
         writeln!(&mut to, "# OPTIONS")?;
-        writeln!(&mut to, "set block-policy {}", &self.block_policy)?;
+        writeln!(&mut to, "set block-policy {}", &self.block_policy_in)?;
         writeln!(&mut to, "set state-policy {}", &self.state_policy)?;
+        if let Some(interface) = &self.loginterface {
+            Self::check_interface(interface)?;
+            writeln!(&mut to, "set loginterface {}", interface)?;
+        }
+        for (option, values, known) in &[
+            ("limit", &self.limits, &Self::LIMITS[..]),
+            ("timeout", &self.timeouts, &Self::TIMEOUTS[..]),
+        ] {
+            if values.is_empty() {
+                continue;
+            }
+            let mut values = values.iter().collect::<Vec<_>>();
+            values.sort();
+            for (name, _) in &values {
+                if !known.contains(&name.as_str()) {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("Invalid {}: `{}`", option, name),
+                    ));
+                }
+            }
+            writeln!(
+                &mut to,
+                "set {} {{ {} }}",
+                option,
+                &values
+                    .iter()
+                    .map(|(k, v)| format!("{} {}", k, v))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            )?;
+        }
         if !self.skip_interfaces.is_empty() {
+            for interface in &self.skip_interfaces {
+                Self::check_interface(interface)?;
+            }
             let interfaces = self.write_macros(&mut to, "skip", &self.skip_interfaces)?;
             writeln!(&mut to, "set skip on {{ {} }}", &interfaces.join(", "))?;
         }
@@ -904,9 +2787,40 @@ impl<'a> Rules {
 
     pub fn write_scrub(&self, mut to: impl IoWrite) -> IoResult<()> {
         writeln!(&mut to, "# SCRUB")?;
-        writeln!(&mut to, "scrub in all")?;
-        if self.min_ttl > 0 {
-            writeln!(&mut to, "scrub out all min-ttl {}", self.min_ttl)?;
+        match self.platform {
+            Platform::Macos | Platform::FreeBsd => {
+                writeln!(&mut to, "{}", self.scrub)?;
+                if self.min_ttl > 0 {
+                    writeln!(&mut to, "scrub out all min-ttl {}", self.min_ttl)?;
+                }
+            }
+            Platform::OpenBsd => {
+                let options = self.scrub.get_match_options();
+                if !options.is_empty() {
+                    writeln!(&mut to, "match in all scrub ({})", options.join(" "))?;
+                }
+                if self.min_ttl > 0 {
+                    writeln!(&mut to, "match out all scrub (min-ttl {})", self.min_ttl)?;
+                }
+            }
+        }
+        writeln!(&mut to)
+    }
+
+    pub fn write_match(&self, mut to: impl IoWrite) -> IoResult<()> {
+        writeln!(&mut to, "# MATCH")?;
+        for rule in &self.match_rules {
+            if rule.prio.is_some_and(|prio| prio > MatchRule::MAX_PRIO) {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "Invalid prio: `{}` (max: {})",
+                        rule.prio.unwrap_or_default(),
+                        MatchRule::MAX_PRIO,
+                    ),
+                ));
+            }
+            writeln!(&mut to, "{}", rule)?;
         }
         writeln!(&mut to)
     }
@@ -918,8 +2832,8 @@ impl<'a> Rules {
                 writeln!(
                     &mut to,
                     "block {} in {} all",
-                    &self.block_policy,
-                    self.get_log()
+                    &self.block_policy_in,
+                    self.get_log(LogSections::INCOMING)
                 )?;
             }
             Action::Pass => {
@@ -933,7 +2847,25 @@ impl<'a> Rules {
         writeln!(&mut to, "# OUTGOING")?;
         match self.outgoing {
             Action::Block => {
-                writeln!(&mut to, "block return out {} all", self.get_log())?;
+                writeln!(
+                    &mut to,
+                    "block {} out {} all",
+                    &self.block_policy_out,
+                    self.get_log(LogSections::OUTGOING),
+                )?;
+                if let Some(tag) = &self.tag {
+                    Self::check_tag(tag)?;
+                    writeln!(
+                        &mut to,
+                        "block {} out {} all ! tagged {} label \"UNTAGGED\"",
+                        &self.block_policy_out,
+                        match &self.log_device {
+                            Some(device) => format!("log (to {})", device),
+                            None => "log".into(),
+                        },
+                        tag,
+                    )?;
+                }
             }
             Action::Pass => {
                 writeln!(&mut to, "pass out all")?;
@@ -944,20 +2876,61 @@ impl<'a> Rules {
 
     pub fn write_antispoofing(&self, mut to: impl IoWrite) -> IoResult<()> {
         writeln!(&mut to, "# ANTISPOOFING")?;
-        if let Some(antispoofing) = &self.antispoofing {
-            writeln!(
-                &mut to,
-                "block drop in {} quick from {} to any label \"ANTISPOOFING\"",
-                self.get_log(),
-                antispoofing,
-            )?;
+        match &self.antispoofing {
+            Some(Antispoofing::Interface(interfaces)) => {
+                let mut interfaces = interfaces.iter().map(|s| s.as_str()).collect::<Vec<_>>();
+                if interfaces.is_empty() {
+                    interfaces = self
+                        .pass_interfaces
+                        .iter()
+                        .map(|s| s.safe_unwrap())
+                        .collect();
+                    interfaces.sort_unstable();
+                    interfaces.dedup();
+                }
+                if interfaces.is_empty() {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidInput,
+                        "Antispoofing requires interfaces",
+                    ));
+                }
+                for interface in &interfaces {
+                    Self::check_interface(interface)?;
+                }
+                writeln!(
+                    &mut to,
+                    "antispoof {} quick for {{ {} }} label \"ANTISPOOFING\"",
+                    self.get_log(LogSections::ANTISPOOFING),
+                    interfaces.join(" "),
+                )?;
+            }
+            Some(antispoofing) => {
+                writeln!(
+                    &mut to,
+                    "block drop in {} quick from {} to any label \"ANTISPOOFING\"",
+                    self.get_log(LogSections::ANTISPOOFING),
+                    antispoofing,
+                )?;
+            }
+            None => {}
         }
         writeln!(&mut to)
     }
 
     pub fn write_blocklist(&self, mut to: impl IoWrite) -> IoResult<()> {
         writeln!(&mut to, "# BLOCKLIST")?;
-        self.write_table(&mut to, &self.block_table_name, &self.block_destinations)?;
+        let mut destinations = self
+            .block_destinations
+            .iter()
+            .map(|s| s.as_str())
+            .collect::<Vec<_>>();
+        if self.is_block_bogons {
+            destinations.extend_from_slice(&gvars::IPV4_BOGON_NETWORKS);
+            if !self.is_block_ipv6 {
+                destinations.extend_from_slice(&gvars::IPV6_BOGON_NETWORKS);
+            }
+        }
+        self.write_table(&mut to, &self.block_table_name, &destinations)?;
         writeln!(
             &mut to,
             "block drop in quick from <{}> to any label \"BLOCKLIST_IN\"",
@@ -977,6 +2950,7 @@ impl<'a> Rules {
         let mut out_interfaces = vec![];
         for direct_interface in &self.pass_interfaces {
             let interface = direct_interface.safe_unwrap();
+            Self::check_interface(interface)?;
             if direct_interface.is_in() {
                 in_interfaces.push(interface);
             } else if direct_interface.is_out() {
@@ -991,15 +2965,20 @@ impl<'a> Rules {
         if !in_interfaces.is_empty() {
             writeln!(
                 &mut to,
-                "pass in quick on {{ {} }} all",
+                "pass in{} on {{ {} }} all{}",
+                self.get_quick(),
                 &in_interfaces.join(", "),
+                self.get_state(),
             )?;
         }
         if !out_interfaces.is_empty() {
             writeln!(
                 &mut to,
-                "pass out quick on {{ {} }} all",
+                "pass out{} on {{ {} }} all{}{}",
+                self.get_quick(),
                 &out_interfaces.join(", "),
+                self.get_tag()?,
+                self.get_state(),
             )?;
         }
         writeln!(&mut to)
@@ -1007,21 +2986,76 @@ impl<'a> Rules {
 
     pub fn write_owners(&self, mut to: impl IoWrite) -> IoResult<()> {
         writeln!(&mut to, "# OWNERS")?;
-        let mut users = vec![];
-        let mut groups = vec![];
-        for owner in &self.pass_owners {
-            if owner.is_group() {
-                &mut groups
-            } else {
-                &mut users
+        for (direction, owners) in &[
+            (
+                "",
+                self.pass_owners
+                    .iter()
+                    .filter(|o| !o.is_in() && !o.is_out())
+                    .collect::<Vec<_>>(),
+            ),
+            (
+                " in",
+                self.pass_owners.iter().filter(|o| o.is_in()).collect(),
+            ),
+            (
+                " out",
+                self.pass_owners.iter().filter(|o| o.is_out()).collect(),
+            ),
+        ] {
+            let mut users = vec![];
+            let mut groups = vec![];
+            for owner in owners {
+                if owner.is_group() {
+                    &mut groups
+                } else {
+                    &mut users
+                }
+                .push(owner.to_rule_item()?);
+            }
+            users.sort_unstable();
+            groups.sort_unstable();
+            if !users.is_empty() {
+                writeln!(
+                    &mut to,
+                    "pass{}{} all user {{ {} }}{}",
+                    direction,
+                    self.get_quick(),
+                    &users.join(", "),
+                    self.get_state(),
+                )?;
+            }
+            if !groups.is_empty() {
+                writeln!(
+                    &mut to,
+                    "pass{}{} all group {{ {} }}{}",
+                    direction,
+                    self.get_quick(),
+                    &groups.join(", "),
+                    self.get_state(),
+                )?;
             }
-            .push(owner.safe_unwrap());
-        }
-        if !users.is_empty() {
-            writeln!(&mut to, "pass quick all user {{ {} }}", &users.join(", "))?;
         }
-        if !groups.is_empty() {
-            writeln!(&mut to, "pass quick all group {{ {} }}", &groups.join(", "))?;
+        writeln!(&mut to)
+    }
+
+    // marked packets pass on any interface unless one is set, keep these narrow
+    pub fn write_tos(&self, mut to: impl IoWrite) -> IoResult<()> {
+        writeln!(&mut to, "# TOS")?;
+        for rule in &self.tos_rules {
+            // unbound, any process setting the tos byte would bypass the lock
+            let interface = rule.interface.as_ref().ok_or_else(|| {
+                io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("TOS rule without interface: `{:#04x}`", rule.tos),
+                )
+            })?;
+            Self::check_interface(interface)?;
+            write!(&mut to, "pass out quick on {}", interface)?;
+            if let Some(proto) = &rule.proto {
+                write!(&mut to, " proto {}", proto)?;
+            }
+            writeln!(&mut to, " all tos {:#04x} label \"TOS\"", rule.tos)?;
         }
         writeln!(&mut to)
     }
@@ -1029,56 +3063,265 @@ impl<'a> Rules {
     pub fn write_ipv6(&self, mut to: impl IoWrite) -> IoResult<()> {
         writeln!(&mut to, "# IPV6")?;
         if self.is_block_ipv6 {
-            writeln!(&mut to, "block {} in quick inet6 all", &self.block_policy)?;
-            writeln!(&mut to, "block return out quick inet6 all")?;
+            if let BlockIpv6::Routable = self.block_ipv6 {
+                writeln!(
+                    &mut to,
+                    "pass quick inet6 from {} to {} label \"IPV6_LOCAL\"",
+                    &Ipv6Addr::LOCALHOST,
+                    &Ipv6Addr::LOCALHOST,
+                )?;
+                writeln!(
+                    &mut to,
+                    "pass quick inet6 from {} to {{ {}, {} }} label \"IPV6_LOCAL\"",
+                    gvars::IPV6_LINK_LOCAL_NETWORK,
+                    gvars::IPV6_LINK_LOCAL_NETWORK,
+                    gvars::IPV6_LINK_LOCAL_MULTICAST,
+                )?;
+            }
+            writeln!(
+                &mut to,
+                "block {} in quick inet6 all",
+                &self.block_policy_in
+            )?;
+            writeln!(
+                &mut to,
+                "block {} out quick inet6 all",
+                &self.block_policy_out
+            )?;
         }
         writeln!(&mut to)
     }
 
-    pub fn write_lan(&self, mut to: impl IoWrite) -> IoResult<()> {
-        writeln!(&mut to, "# LAN")?;
-        if let Some(lan) = &self.lan {
-            let ipv4nrm = gvars::IPV4_NOT_ROUTABLE_MULTICASTS.join(", ");
-            let ipv6nrm = gvars::IPV6_NOT_ROUTABLE_MULTICASTS.join(", ");
-            let (ipv4m, ipv6m): (&str, &str) = match lan.multicast {
-                Multicast::NotRoutable => (&ipv4nrm, &ipv6nrm),
-                Multicast::All => (gvars::IPV4_MULTICAST, gvars::IPV6_MULTICAST),
-            };
-            if lan.is_block_out_dns {
-                let mut block_out_dns = |addrs: &[&str]| -> IoResult<()> {
-                    for &addr in addrs {
-                        writeln!(
-                            &mut to,
-                            "block return out quick {} proto {{ tcp, udp }} from {} to {} port domain",
-                            if addr.contains(':') { "inet6" } else { "inet" }, addr, addr,
-                        )?;
-                    }
-                    Ok(())
-                };
-                block_out_dns(&gvars::IPV4_PRIVATE_NETWORKS)?;
-                if !self.is_block_ipv6 {
-                    block_out_dns(&gvars::IPV6_PRIVATE_NETWORKS)?;
+    pub fn write_ipv4(&self, mut to: impl IoWrite) -> IoResult<()> {
+        writeln!(&mut to, "# IPV4")?;
+        if self.is_block_ipv4 {
+            writeln!(&mut to, "block {} in quick inet all", &self.block_policy_in)?;
+            writeln!(
+                &mut to,
+                "block {} out quick inet all",
+                &self.block_policy_out
+            )?;
+        }
+        writeln!(&mut to)
+    }
+
+    pub fn write_dns(&self, mut to: impl IoWrite) -> IoResult<()> {
+        writeln!(&mut to, "# DNS")?;
+        if let Some(gateway) = &self.dns_gateway {
+            writeln!(
+                &mut to,
+                "pass out quick {} proto {{ tcp, udp }} from any to {} port domain",
+                if gateway.contains(':') {
+                    "inet6"
+                } else {
+                    "inet"
+                },
+                gateway,
+            )?;
+        }
+        let mut ipv4_servers = vec![];
+        let mut ipv6_servers = vec![];
+        for server in &self.dns_servers {
+            match server.parse::<IpAddr>() {
+                Ok(IpAddr::V4(_)) => ipv4_servers.push(server.as_str()),
+                Ok(IpAddr::V6(_)) => ipv6_servers.push(server.as_str()),
+                Err(_) => {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("Invalid dns server: `{}`", server),
+                    ))
                 }
             }
-            for addr in &gvars::IPV4_PRIVATE_NETWORKS {
+        }
+        if self.is_block_ipv6 {
+            ipv6_servers.clear();
+        }
+        for (af, servers) in [("inet", ipv4_servers), ("inet6", ipv6_servers)] {
+            if !servers.is_empty() {
                 writeln!(
                     &mut to,
-                    "pass quick inet from {} to {{ {}, {}, {} }}",
-                    addr,
-                    addr,
-                    &Ipv4Addr::BROADCAST,
-                    ipv4m,
+                    "pass out quick {} proto {{ tcp, udp }} from any to {{ {} }} port domain",
+                    af,
+                    servers.join(", "),
                 )?;
             }
+        }
+        writeln!(&mut to)
+    }
+
+    pub fn write_ports(&self, mut to: impl IoWrite) -> IoResult<()> {
+        writeln!(&mut to, "# PORTS")?;
+        for spec in &self.pass_out_ports {
             writeln!(
                 &mut to,
-                "pass quick inet from {} to {{ {}, {} }}",
-                &Ipv4Addr::UNSPECIFIED,
-                &Ipv4Addr::BROADCAST,
-                &ipv4nrm,
+                "pass out quick proto {} from any to any port {}",
+                spec.proto.as_deref().unwrap_or("{ tcp, udp }"),
+                spec.port,
             )?;
+        }
+        writeln!(&mut to)
+    }
+
+    // discovery is broadcast and lease renewal goes to the server directly,
+    // so the local subnets (private networks without lan subnets) are passed too
+    pub fn write_dhcp(&self, mut to: impl IoWrite) -> IoResult<()> {
+        writeln!(&mut to, "# DHCP")?;
+        if self.is_pass_dhcp || self.is_isolate_lan {
+            let broadcast = Ipv4Addr::BROADCAST.to_string();
+            let networks = match &self.lan {
+                Some(lan) => lan.get_networks().0,
+                None => gvars::IPV4_PRIVATE_NETWORKS.to_vec(),
+            };
+            writeln!(
+                &mut to,
+                "pass out quick inet proto udp from any port bootpc to {{ {} }} port bootps label \"DHCP\"",
+                [&[broadcast.as_str()][..], &networks].concat().join(", "),
+            )?;
+            writeln!(
+                &mut to,
+                "pass in quick inet proto udp from any port bootps to any port bootpc label \"DHCP\"",
+            )?;
+            if !self.is_block_ipv6 {
+                writeln!(
+                    &mut to,
+                    "pass out quick inet6 proto udp from {} port dhcpv6-client to {} port dhcpv6-server label \"DHCP6\"",
+                    gvars::IPV6_LINK_LOCAL_NETWORK,
+                    gvars::IPV6_LINK_LOCAL_MULTICAST,
+                )?;
+                writeln!(
+                    &mut to,
+                    "pass in quick inet6 proto udp from {} port dhcpv6-server to {} port dhcpv6-client label \"DHCP6\"",
+                    gvars::IPV6_LINK_LOCAL_NETWORK,
+                    gvars::IPV6_LINK_LOCAL_NETWORK,
+                )?;
+            }
+        }
+        writeln!(&mut to)
+    }
+
+    // pf does not filter arp, so dhcp (and ndp for ipv6) is all that has to pass
+    pub fn write_isolation(&self, mut to: impl IoWrite) -> IoResult<()> {
+        writeln!(&mut to, "# ISOLATION")?;
+        if self.is_isolate_lan {
+            let ipv4 = [
+                &gvars::IPV4_PRIVATE_NETWORKS[..],
+                &[gvars::IPV4_MULTICAST, &Ipv4Addr::BROADCAST.to_string()],
+            ]
+            .concat()
+            .join(", ");
+            writeln!(
+                &mut to,
+                "block return out quick inet from any to {{ {} }} label \"LAN_ISOLATION\"",
+                &ipv4,
+            )?;
+            writeln!(
+                &mut to,
+                "block in quick inet from {{ {} }} to any label \"LAN_ISOLATION\"",
+                gvars::IPV4_PRIVATE_NETWORKS.join(", "),
+            )?;
+            if !self.is_block_ipv6 {
+                writeln!(
+                    &mut to,
+                    "pass quick inet6 proto icmp6 all icmp6-type {{ neighbrsol, neighbradv, routersol, routeradv }} label \"NDP\"",
+                )?;
+                let ipv6 = [&gvars::IPV6_PRIVATE_NETWORKS[..], &[gvars::IPV6_MULTICAST]]
+                    .concat()
+                    .join(", ");
+                writeln!(
+                    &mut to,
+                    "block return out quick inet6 from any to {{ {} }} label \"LAN_ISOLATION\"",
+                    &ipv6,
+                )?;
+                writeln!(
+                    &mut to,
+                    "block in quick inet6 from {{ {} }} to any label \"LAN_ISOLATION\"",
+                    gvars::IPV6_PRIVATE_NETWORKS.join(", "),
+                )?;
+            }
+        }
+        writeln!(&mut to)
+    }
+
+    // traffic to own addresses goes through loopback, only needed if it is not skipped
+    pub fn write_self(&self, mut to: impl IoWrite) -> IoResult<()> {
+        writeln!(&mut to, "# SELF")?;
+        let mut addresses = self.self_addresses.iter().collect::<Vec<_>>();
+        addresses.sort_unstable();
+        for addr in addresses {
+            if addr.parse::<IpAddr>().is_err() {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Invalid self address: `{}`", addr),
+                ));
+            }
+            writeln!(
+                &mut to,
+                "pass quick {} from {} to {} label \"SELF\"",
+                if addr.contains(':') { "inet6" } else { "inet" },
+                addr,
+                addr,
+            )?;
+        }
+        writeln!(&mut to)
+    }
+
+    pub fn write_lan(&self, mut to: impl IoWrite) -> IoResult<()> {
+        writeln!(&mut to, "# LAN")?;
+        if let Some(lan) = self.lan.as_ref().filter(|_| !self.is_isolate_lan) {
+            for subnet in &lan.subnets {
+                if !is_address(subnet) {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("Invalid lan subnet: `{}`", subnet),
+                    ));
+                }
+            }
+            let (ipv4_networks, ipv6_networks) = lan.get_networks();
+            let ipv4nrm = gvars::IPV4_NOT_ROUTABLE_MULTICASTS.join(", ");
+            let ipv6nrm = gvars::IPV6_NOT_ROUTABLE_MULTICASTS.join(", ");
+            let (ipv4m, ipv6m): (&str, &str) = match lan.multicast {
+                Multicast::NotRoutable => (&ipv4nrm, &ipv6nrm),
+                Multicast::All => (gvars::IPV4_MULTICAST, gvars::IPV6_MULTICAST),
+            };
+            if lan.is_block_out_dns {
+                let mut block_out_dns = |addrs: &[&str]| -> IoResult<()> {
+                    for &addr in addrs {
+                        writeln!(
+                            &mut to,
+                            "block return out quick {} proto {{ tcp, udp }} from {} to {} port domain",
+                            if addr.contains(':') { "inet6" } else { "inet" }, addr, addr,
+                        )?;
+                    }
+                    Ok(())
+                };
+                if !self.is_block_ipv4 {
+                    block_out_dns(&ipv4_networks)?;
+                }
+                if !self.is_block_ipv6 {
+                    block_out_dns(&ipv6_networks)?;
+                }
+            }
+            if !self.is_block_ipv4 {
+                for addr in &ipv4_networks {
+                    writeln!(
+                        &mut to,
+                        "pass quick inet from {} to {{ {}, {}, {} }}",
+                        addr,
+                        addr,
+                        &Ipv4Addr::BROADCAST,
+                        ipv4m,
+                    )?;
+                }
+                writeln!(
+                    &mut to,
+                    "pass quick inet from {} to {{ {}, {} }}",
+                    &Ipv4Addr::UNSPECIFIED,
+                    &Ipv4Addr::BROADCAST,
+                    &ipv4nrm,
+                )?;
+            }
             if !self.is_block_ipv6 {
-                for addr in &gvars::IPV6_PRIVATE_NETWORKS {
+                for addr in &ipv6_networks {
                     writeln!(
                         &mut to,
                         "pass quick inet6 from {} to {{ {}, {} }}",
@@ -1092,40 +3335,101 @@ impl<'a> Rules {
                     &ipv6nrm,
                 )?;
             }
+            if lan.is_pass_service_discovery {
+                let mut discovery = vec![];
+                if !self.is_block_ipv4 {
+                    discovery.push((&ipv4_networks, gvars::MDNS_IPV4_ADDRESS, gvars::MDNS_PORT));
+                    discovery.push((&ipv4_networks, gvars::SSDP_IPV4_ADDRESS, gvars::SSDP_PORT));
+                }
+                if !self.is_block_ipv6 {
+                    discovery.push((&ipv6_networks, gvars::MDNS_IPV6_ADDRESS, gvars::MDNS_PORT));
+                }
+                for (networks, addr, port) in
+                    discovery.into_iter().filter(|(n, _, _)| !n.is_empty())
+                {
+                    writeln!(
+                        &mut to,
+                        "pass quick {} proto udp from {{ {} }} to {} port {} label \"SERVICE_DISCOVERY\"",
+                        if addr.contains(':') { "inet6" } else { "inet" },
+                        networks.join(", "),
+                        addr,
+                        port,
+                    )?;
+                }
+            }
+        }
+        writeln!(&mut to)
+    }
+
+    // airdrop, handoff and the rest of continuity run over awdl/llw and ipv6 link-local
+    #[cfg(target_os = "macos")]
+    pub fn write_continuity(&self, mut to: impl IoWrite) -> IoResult<()> {
+        writeln!(&mut to, "# CONTINUITY")?;
+        if self.is_pass_continuity {
+            writeln!(
+                &mut to,
+                "pass quick on {{ {} }} all label \"CONTINUITY\"",
+                gvars::CONTINUITY_INTERFACES.join(", "),
+            )?;
+            if !self.is_block_ipv6 {
+                writeln!(
+                    &mut to,
+                    "pass quick inet6 from {} to {{ {}, {} }} label \"CONTINUITY\"",
+                    gvars::IPV6_LINK_LOCAL_NETWORK,
+                    gvars::IPV6_LINK_LOCAL_NETWORK,
+                    gvars::IPV6_LINK_LOCAL_MULTICAST,
+                )?;
+            }
         }
         writeln!(&mut to)
     }
 
     pub fn write_icmp(&self, mut to: impl IoWrite) -> IoResult<()> {
         writeln!(&mut to, "# ICMP")?;
-        match self.icmp {
-            Some(ICMP::Echoreq) => {
-                let mut pass_icmp = |af: &str, proto: &str, type_prefix: &str, label: &str| {
-                    writeln!(
-                        &mut to,
-                        "pass quick {} proto {} all {} echoreq label \"{}\"",
-                        af, proto, type_prefix, label,
-                    )
-                };
-                pass_icmp("inet", "icmp", "icmp-type", "ICMP")?;
-                if !self.is_block_ipv6 {
-                    pass_icmp("inet6", "icmp6", "icmp6-type", "ICMP6")?;
+        if let Some(icmp) = &self.icmp {
+            let mut pass_icmp = |af: &str, proto: &str, types: &[String], label: &str| {
+                if let Some(s) = types
+                    .iter()
+                    .find(|s| s.is_empty() || !s.chars().all(|c| c.is_ascii_alphanumeric()))
+                {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("Invalid {} type: `{}`", proto, s),
+                    ));
                 }
-            }
-            Some(ICMP::All) => {
-                let mut pass_icmp = |af: &str, proto: &str, label: &str| {
-                    writeln!(
-                        &mut to,
-                        "pass quick {} proto {} all label \"{}\"",
-                        af, proto, label,
-                    )
+                let types = match types {
+                    [] => String::new(),
+                    [s] => format!(" {}-type {}", proto, s),
+                    _ => format!(" {}-type {{ {} }}", proto, types.join(", ")),
                 };
-                pass_icmp("inet", "icmp", "ICMP")?;
-                if !self.is_block_ipv6 {
-                    pass_icmp("inet6", "icmp6", "ICMP6")?;
-                }
+                writeln!(
+                    &mut to,
+                    "pass quick {} proto {} all{} label \"{}\"",
+                    af, proto, types, label,
+                )
+            };
+            if !self.is_block_ipv4 {
+                pass_icmp("inet", "icmp", &icmp.types, "ICMP")?;
+            }
+            if !self.is_block_ipv6 {
+                pass_icmp("inet6", "icmp6", &icmp.types6, "ICMP6")?;
+            }
+        }
+        // errors for our own flows already pass by state matching, this lets them in
+        // statelessly (pmtu discovery, traceroute) at the cost of accepting forged ones
+        if self.is_pass_icmp_errors {
+            if !self.is_block_ipv4 {
+                writeln!(
+                    &mut to,
+                    "pass in quick inet proto icmp all icmp-type {{ unreach, timex }} no state label \"ICMP_ERRORS\"",
+                )?;
+            }
+            if !self.is_block_ipv6 {
+                writeln!(
+                    &mut to,
+                    "pass in quick inet6 proto icmp6 all icmp6-type {{ unreach, toobig, timex }} no state label \"ICMP6_ERRORS\"",
+                )?;
             }
-            _ => {}
         }
         writeln!(&mut to)
     }
@@ -1147,90 +3451,2108 @@ impl<'a> Rules {
         }
         self.write_table(&mut to, &self.in_table_name, &in_destinations)?;
         self.write_table(&mut to, &self.out_table_name, &out_destinations)?;
+        let in_state = match &self.inbound_protection {
+            Some(protection) => {
+                if let PassState::No = self.pass_state {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidInput,
+                        "Inbound protection requires keep state",
+                    ));
+                }
+                let table_name = &protection.overload_table_name;
+                writeln!(&mut to, "table <{}> persist", table_name)?;
+                writeln!(
+                    &mut to,
+                    "block {} in quick from <{}>",
+                    &self.block_policy_in, table_name,
+                )?;
+                format!(" {}", protection)
+            }
+            None => self.get_state(),
+        };
         writeln!(
             &mut to,
-            "pass in quick from <{}> to any",
+            "pass in{} from <{}> to any{}",
+            self.get_quick(),
             &self.in_table_name,
+            &in_state,
         )?;
-        writeln!(
-            &mut to,
-            "pass out quick from any to <{}>",
-            &self.out_table_name,
-        )?;
-        writeln!(&mut to)
-    }
-
-    fn write_macros(
-        &self,
-        mut to: impl IoWrite,
-        prefix: &str,
-        interfaces: impl IntoIterator<Item = impl AsRef<str>>,
-    ) -> IoResult<Vec<String>> {
-        let mut macros = vec![];
-        for (idx, interface) in interfaces.into_iter().enumerate() {
-            let macro_var = &format!("{}{}_if", prefix, &idx);
-            writeln!(&mut to, "{} = \"{}\"", macro_var, interface.as_ref())?;
-            macros.push(format!("${}", macro_var));
+        let route_to = match &self.route_to {
+            Some((interface, gateway)) => {
+                Self::check_interface(interface)?;
+                if interface.is_empty() || gateway.parse::<IpAddr>().is_err() {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("Invalid route-to: `({} {})`", interface, gateway),
+                    ));
+                }
+                format!(" route-to ({} {})", interface, gateway)
+            }
+            None => String::new(),
+        };
+        if !self.is_strict_egress || !out_destinations.is_empty() {
+            writeln!(
+                &mut to,
+                "pass out{}{} from any to <{}>{}{}",
+                self.get_quick(),
+                &route_to,
+                &self.out_table_name,
+                self.get_tag()?,
+                self.get_state(),
+            )?;
         }
-        Ok(macros)
+        let mut interface_destinations = self.interface_destinations.iter().collect::<Vec<_>>();
+        interface_destinations.sort_unstable_by_key(|(interface, _)| *interface);
+        for (interface, destinations) in interface_destinations {
+            if destinations.is_empty() {
+                continue;
+            }
+            Self::check_interface(interface)?;
+            if interface.is_empty() || !interface.chars().all(|c| c.is_ascii_alphanumeric()) {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Invalid interface: `{}`", interface),
+                ));
+            }
+            let table_name = format!("{}_{}", &self.out_table_name, interface);
+            self.write_table(&mut to, &table_name, destinations)?;
+            writeln!(
+                &mut to,
+                "pass out{} on {} from any to <{}>{}{}",
+                self.get_quick(),
+                interface,
+                &table_name,
+                self.get_tag()?,
+                self.get_state(),
+            )?;
+        }
+        let mut remotes = self.pass_remotes.iter().collect::<Vec<_>>();
+        remotes.sort_unstable_by_key(|r| r.to_string());
+        for remote in remotes {
+            for host in self.resolve_hostname(&mut to, remote.host())? {
+                write!(
+                    &mut to,
+                    "pass out{} proto {} from any to {}",
+                    self.get_quick(),
+                    remote.proto().unwrap_or("{ tcp, udp }"),
+                    host,
+                )?;
+                if let Some(port) = remote.port() {
+                    write!(&mut to, " port {}", port)?;
+                }
+                writeln!(&mut to, "{}{}", self.get_tag()?, self.get_state())?;
+            }
+        }
+        writeln!(&mut to)
     }
 
-    fn write_table(
-        &self,
-        mut to: impl IoWrite,
-        table_name: &str,
-        destinations: impl IntoIterator<Item = impl AsRef<str>>,
-    ) -> IoResult<()> {
-        let mut addresses = vec![];
-        let mut files = vec![];
-        for destination in destinations {
-            let destination = destination.as_ref();
-            if destination.starts_with('/') {
-                files.push(format!("file \"{}\"", destination));
-            } else {
-                addresses.push(destination.to_string());
+    pub fn write_profiles(&self, mut to: impl IoWrite) -> IoResult<()> {
+        writeln!(&mut to, "# PROFILES")?;
+        let mut profiles = self.profile_destinations.iter().collect::<Vec<_>>();
+        profiles.sort_unstable_by_key(|(profile, _)| *profile);
+        for (profile, destinations) in profiles {
+            if destinations.is_empty() {
+                continue;
             }
+            let mut destinations = destinations.iter().cloned().collect::<Vec<_>>();
+            destinations.sort_unstable();
+            writeln!(&mut to, "anchor \"{}\" {{", profile)?;
+            writeln!(
+                &mut to,
+                "  pass out quick from any to {{ {} }}",
+                destinations.join(", "),
+            )?;
+            writeln!(&mut to, "}}")?;
         }
-        writeln!(
-            &mut to,
-            "table <{}> {{ {} }} {}",
-            table_name,
-            addresses.join(", "),
-            files.join(" "),
-        )?;
-        Ok(())
+        writeln!(&mut to)
     }
 
-    fn get_log(&self) -> &str {
-        if self.is_enable_log {
-            "log"
-        } else {
-            ""
+    pub fn write_extra_rules(&self, mut to: impl IoWrite) -> IoResult<()> {
+        writeln!(&mut to, "# EXTRA")?;
+        for rule in &self.extra_rules {
+            writeln!(&mut to, "{}", rule)?;
         }
+        writeln!(&mut to)
     }
-}
 
-impl Default for Rules {
-    fn default() -> Self {
-        Self {
-            block_table_name: Self::DEFAULT_BLOCK_TABLE_NAME.into(),
-            in_table_name: Self::DEFAULT_IN_TABLE_NAME.into(),
-            out_table_name: Self::DEFAULT_OUT_TABLE_NAME.into(),
-            block_policy: Default::default(),
-            state_policy: Default::default(),
-            min_ttl: 0,
-            is_enable_log: false,
-            incoming: Default::default(),
-            outgoing: Default::default(),
-            antispoofing: Some(Default::default()),
-            is_block_ipv6: false,
-            lan: Some(Default::default()),
-            icmp: Some(Default::default()),
-            skip_interfaces: Default::default(),
-            pass_interfaces: Default::default(),
-            pass_owners: Default::default(),
-            block_destinations: Default::default(),
-            pass_destinations: Default::default(),
+    // best-effort, only recognizes what `write` emits, the rest goes to `extra_rules`
+    pub fn from_pf_conf(conf: &str) -> Self {
+        let mut rules = Self {
+            antispoofing: None,
+            lan: None,
+            icmp: None,
+            ..Default::default()
+        };
+        let mut macros = HashMap::new();
+        let mut tables = HashMap::new();
+        let mut in_interfaces = vec![];
+        let mut out_interfaces = vec![];
+        let mut in_table = None;
+        let mut out_table = None;
+        let mut interface_tables = vec![];
+        let mut overload_blocks = vec![];
+        let parse_list = |line: &str| -> Vec<String> {
+            line.split_once('{')
+                .and_then(|(_, s)| s.split_once('}'))
+                .map(|(s, _)| {
+                    s.split(',')
+                        .map(|s| s.trim().to_string())
+                        .filter(|s| !s.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+        let expand = |macros: &HashMap<String, String>, s: String| match s.strip_prefix('$') {
+            Some(name) => macros.get(name).cloned().unwrap_or(s),
+            None => s,
+        };
+        let parse_table_name =
+            |s: &str| s.trim_start_matches('<').trim_end_matches('>').to_string();
+        let parse_icmp_types = |line: &str, type_prefix: &str| -> Vec<String> {
+            let types = match line.split_once(&format!(" {} ", type_prefix)) {
+                Some((_, s)) => s.split(" label ").next().unwrap_or_default().trim(),
+                None => return vec![],
+            };
+            match types.strip_prefix('{') {
+                Some(s) => s
+                    .trim_end_matches('}')
+                    .split(',')
+                    .map(|s| s.trim().to_string())
+                    .filter(|s| !s.is_empty())
+                    .collect(),
+                None => vec![types.to_string()],
+            }
+        };
+        let no_lan = || Lan {
+            is_block_out_dns: false,
+            is_pass_service_discovery: false,
+            multicast: Default::default(),
+            subnets: Default::default(),
+        };
+        let mut lines = conf.lines();
+        while let Some(line) = lines.next() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut words = line.split_whitespace().collect::<Vec<_>>();
+            let label = line
+                .rsplit_once("label ")
+                .map(|(_, s)| s.trim().trim_matches('"'));
+            if label.is_none() && words.ends_with(&["no", "state"]) {
+                words.truncate(words.len() - 2);
+                rules.pass_state = PassState::No;
+            }
+            if let Some(i) = words.iter().position(|&s| s == "(to") {
+                if let Some(device) = words.get(i + 1) {
+                    rules.log_device = Some(device.trim_end_matches(')').into());
+                }
+                words.drain(i..words.len().min(i + 2));
+            }
+            if words.first() == Some(&"pass") {
+                if let Some(i) = words.iter().position(|&s| s == "tag") {
+                    if let Some(tag) = words.get(i + 1) {
+                        rules.tag = Some(tag.to_string());
+                    }
+                    words.drain(i..words.len().min(i + 2));
+                }
+            }
+            // non-quick passes are matched as their quick form
+            if label.is_none()
+                && words.first() == Some(&"pass")
+                && !words.contains(&"quick")
+                && !matches!(words[..], ["pass", "in" | "out", "all"])
+            {
+                let i = if matches!(words.get(1), Some(&"in") | Some(&"out")) {
+                    2
+                } else {
+                    1
+                };
+                words.insert(i, "quick");
+                rules.is_quick = false;
+            }
+            let log = words.contains(&"log");
+            match (words.as_slice(), label) {
+                ([name, "=", ..], _) => {
+                    if let Some((_, value)) = line.split_once('=') {
+                        macros.insert(name.to_string(), value.trim().trim_matches('"').to_string());
+                    }
+                }
+                (["set", "block-policy", BlockPolicy::DROP], _) => {
+                    rules.block_policy_in = BlockPolicy::Drop
+                }
+                (["set", "block-policy", BlockPolicy::RETURN], _) => {
+                    rules.block_policy_in = BlockPolicy::Return
+                }
+                (["set", "loginterface", interface], _) => {
+                    rules.loginterface = Some(interface.to_string())
+                }
+                (["set", "state-policy", StatePolicy::IF_BOUND], _) => {
+                    rules.state_policy = StatePolicy::IfBound
+                }
+                (["set", "state-policy", StatePolicy::FLOATING], _) => {
+                    rules.state_policy = StatePolicy::Floating
+                }
+                (["set", option @ ("limit" | "timeout"), ..], _) => {
+                    let values = if *option == "limit" {
+                        &mut rules.limits
+                    } else {
+                        &mut rules.timeouts
+                    };
+                    for value in parse_list(line) {
+                        if let Some((k, v)) = value.split_once(' ') {
+                            if let Ok(v) = v.trim().parse() {
+                                values.insert(k.into(), v);
+                            }
+                        }
+                    }
+                }
+                (["set", "skip", "on", ..], _) => rules
+                    .skip_interfaces
+                    .extend(parse_list(line).into_iter().map(|s| expand(&macros, s))),
+                (["scrub", "in", "all", options @ ..], _)
+                    if Scrub::from_options(options).is_some() =>
+                {
+                    rules.scrub = Scrub::from_options(options).unwrap_or_default()
+                }
+                (["scrub", "out", "all", "min-ttl", ttl], _) if ttl.parse::<u8>().is_ok() => {
+                    rules.min_ttl = ttl.parse().unwrap_or_default()
+                }
+                (["match", "in", "all", "scrub", options @ ..], _)
+                    if Scrub::from_match_options(options).is_some() =>
+                {
+                    rules.platform = Platform::OpenBsd;
+                    rules.scrub = Scrub::from_match_options(options).unwrap_or_default()
+                }
+                (["match", "out", "all", "scrub", "(min-ttl", ttl], _)
+                    if ttl.trim_end_matches(')').parse::<u8>().is_ok() =>
+                {
+                    rules.platform = Platform::OpenBsd;
+                    rules.min_ttl = ttl.trim_end_matches(')').parse().unwrap_or_default()
+                }
+                (["table", name, ..], _) => {
+                    let mut destinations = parse_list(line);
+                    if let Some((_, files)) = line.rsplit_once('}') {
+                        destinations.extend(
+                            files
+                                .split("file ")
+                                .map(|s| s.trim().trim_matches('"').to_string())
+                                .filter(|s| !s.is_empty()),
+                        );
+                    }
+                    tables.insert(parse_table_name(name), destinations);
+                }
+                (["block", _, "in", rest @ .., "all"], None)
+                    if rest.iter().all(|&w| w == "log") =>
+                {
+                    rules.incoming = Action::Block;
+                    if log {
+                        rules.log = rules.log | LogSections::INCOMING;
+                    }
+                }
+                (["pass", "in", "all"], None) => rules.incoming = Action::Pass,
+                (["block", policy, "out", rest @ .., "all"], None)
+                    if rest.iter().all(|&w| w == "log") =>
+                {
+                    rules.outgoing = Action::Block;
+                    if *policy == BlockPolicy::DROP {
+                        rules.block_policy_out = BlockPolicy::Drop;
+                    }
+                    if log {
+                        rules.log = rules.log | LogSections::OUTGOING;
+                    }
+                }
+                (["pass", "out", "all"], None) => rules.outgoing = Action::Pass,
+                (_, Some("UNTAGGED")) => {
+                    if let Some(i) = words.iter().position(|&s| s == "tagged") {
+                        rules.tag = words.get(i + 1).map(|s| s.to_string());
+                    }
+                }
+                (_, Some("ANTISPOOFING")) => {
+                    rules.antispoofing = if words[0] == "antispoof" {
+                        Some(Antispoofing::Interface(
+                            line.split_once(" for ")
+                                .and_then(|(_, s)| s.split(" label ").next())
+                                .unwrap_or_default()
+                                .split(|c: char| c.is_whitespace() || c == '{' || c == '}')
+                                .filter(|s| !s.is_empty())
+                                .map(|s| s.into())
+                                .collect(),
+                        ))
+                    } else if line.contains(Antispoofing::NO_ROUTE) {
+                        Some(Antispoofing::NoRoute)
+                    } else {
+                        Some(Antispoofing::UrpfFailed)
+                    };
+                    if log {
+                        rules.log = rules.log | LogSections::ANTISPOOFING;
+                    }
+                }
+                (["block", "drop", "in", "quick", "from", name, ..], Some("BLOCKLIST_IN")) => {
+                    rules.block_table_name = parse_table_name(name)
+                }
+                (_, Some("BLOCKLIST_OUT")) => {}
+                (["pass", "out", "quick", "on", interface, "from", "any", "to", name], None) => {
+                    interface_tables.push((interface.to_string(), parse_table_name(name)))
+                }
+                (["pass", direction, "quick", "on", ..], None) => {
+                    let interfaces = parse_list(line).into_iter().map(|s| expand(&macros, s));
+                    match *direction {
+                        "in" => in_interfaces.extend(interfaces),
+                        "out" => out_interfaces.extend(interfaces),
+                        _ => rules.extra_rules.push(line.into()),
+                    }
+                }
+                (["pass", "quick", "all", "user", ..], None) => rules.pass_owners.extend(
+                    parse_list(line)
+                        .iter()
+                        .map(|s| Owner::new(format!("{}{}", Owner::USER, s))),
+                ),
+                (["pass", "quick", "all", "group", ..], None) => rules.pass_owners.extend(
+                    parse_list(line)
+                        .iter()
+                        .map(|s| Owner::new(format!("{}{}", Owner::GROUP, s))),
+                ),
+                (
+                    ["pass", direction @ ("in" | "out"), "quick", "all", kind @ ("user" | "group"), ..],
+                    None,
+                ) => {
+                    let direction = if *direction == "in" {
+                        Direction::IN
+                    } else {
+                        Direction::OUT
+                    };
+                    let kind = if *kind == "user" {
+                        Owner::USER
+                    } else {
+                        Owner::GROUP
+                    };
+                    rules.pass_owners.extend(
+                        parse_list(line)
+                            .iter()
+                            .map(|s| Owner::new(format!("{}{}{}", direction, kind, s))),
+                    )
+                }
+                (["block", _, "in", "quick", "inet6", "all"], None)
+                | (["block", _, "out", "quick", "inet6", "all"], None) => {
+                    rules.is_block_ipv6 = true
+                }
+                (["block", _, "in", "quick", "inet", "all"], None)
+                | (["block", _, "out", "quick", "inet", "all"], None) => rules.is_block_ipv4 = true,
+                (
+                    ["pass", "out", "quick", _, "proto", .., "to", gateway, "port", "domain"],
+                    None,
+                ) => rules.dns_gateway = Some(gateway.to_string()),
+                (["pass", "out", "quick", _, "proto", .., "}", "port", "domain"], None) => {
+                    if let Some((_, servers)) = line.split_once(" to ") {
+                        rules.dns_servers.extend(parse_list(servers));
+                    }
+                }
+                (["block", "return", "out", "quick", _, "proto", .., "port", "domain"], None) => {
+                    rules.lan.get_or_insert_with(no_lan).is_block_out_dns = true
+                }
+                (["pass", "quick", "inet", "from", ..], None)
+                | (["pass", "quick", "inet6", "from", ..], None) => {
+                    let lan = rules.lan.get_or_insert_with(no_lan);
+                    if line.contains(gvars::IPV4_MULTICAST) || line.contains(gvars::IPV6_MULTICAST)
+                    {
+                        lan.multicast = Multicast::All;
+                    }
+                    let network = words[4];
+                    if is_address(network)
+                        && network != Ipv4Addr::UNSPECIFIED.to_string()
+                        && network != Ipv6Addr::UNSPECIFIED.to_string()
+                        && !gvars::IPV4_PRIVATE_NETWORKS.contains(&network)
+                        && !gvars::IPV6_PRIVATE_NETWORKS.contains(&network)
+                    {
+                        lan.subnets.insert(network.into());
+                    }
+                }
+                (_, Some("ICMP")) => {
+                    let types = parse_icmp_types(line, "icmp-type");
+                    rules.icmp = Some(ICMP {
+                        types6: types.clone(),
+                        types,
+                    })
+                }
+                (_, Some("ICMP6")) => {
+                    let types6 = parse_icmp_types(line, "icmp6-type");
+                    match &mut rules.icmp {
+                        Some(icmp) => icmp.types6 = types6,
+                        // no icmp4 rule when ipv4 is blocked
+                        None => {
+                            rules.icmp = Some(ICMP {
+                                types: types6.clone(),
+                                types6,
+                            })
+                        }
+                    }
+                }
+                (_, Some("ICMP_ERRORS")) => rules.is_pass_icmp_errors = true,
+                (_, Some("LAN_ISOLATION")) => rules.is_isolate_lan = true,
+                (_, Some("SERVICE_DISCOVERY")) => {
+                    rules
+                        .lan
+                        .get_or_insert_with(no_lan)
+                        .is_pass_service_discovery = true
+                }
+                #[cfg(target_os = "macos")]
+                (_, Some("CONTINUITY")) => rules.is_pass_continuity = true,
+                (["pass", "quick", _, "from", addr, ..], Some("SELF")) => {
+                    rules.self_addresses.insert(addr.to_string());
+                }
+                (_, Some("DHCP")) => rules.is_pass_dhcp = true,
+                (_, Some("DHCP6")) | (_, Some("NDP")) => {}
+                (_, Some("IPV6_LOCAL")) => rules.block_ipv6 = BlockIpv6::Routable,
+                (_, Some("ICMP6_ERRORS")) => rules.is_pass_icmp_errors = true,
+                (["pass", "in", "quick", "from", name, "to", "any"], None) => {
+                    in_table = Some(parse_table_name(name))
+                }
+                (["pass", "in", "quick", "from", name, "to", "any", "keep", "state", ..], None)
+                    if InboundProtection::from_options(line).is_some() =>
+                {
+                    in_table = Some(parse_table_name(name));
+                    rules.inbound_protection = InboundProtection::from_options(line);
+                }
+                // the overload block comes before the pass in rule that names its table
+                (["block", _, "in", "quick", "from", name], None) => {
+                    overload_blocks.push((parse_table_name(name), line))
+                }
+                (["pass", "out", "quick", "from", "any", "to", name], None) => {
+                    out_table = Some(parse_table_name(name))
+                }
+                (
+                    ["pass", "out", "quick", "proto", proto, .., "from", "any", "to", "any", "port", port],
+                    None,
+                ) => match port.parse() {
+                    Ok(port) => rules.pass_out_ports.push(PortSpec {
+                        proto: Some(proto.to_string()).filter(|s| s != "{"),
+                        port,
+                    }),
+                    Err(_) => rules.extra_rules.push(line.into()),
+                },
+                (["pass", "out", "quick", "proto", proto, .., "from", "any", "to", host], None) => {
+                    rules
+                        .pass_remotes
+                        .insert(Remote::new(*host, None, Some(proto)));
+                }
+                (
+                    ["pass", "out", "quick", "proto", proto, .., "from", "any", "to", host, "port", port],
+                    None,
+                ) => {
+                    rules
+                        .pass_remotes
+                        .insert(Remote::new(*host, port.parse().ok(), Some(proto)));
+                }
+                (
+                    ["pass", "out", "quick", "route-to", interface, gateway, "from", "any", "to", name],
+                    None,
+                ) => {
+                    rules.route_to = Some((
+                        interface.trim_start_matches('(').into(),
+                        gateway.trim_end_matches(')').into(),
+                    ));
+                    out_table = Some(parse_table_name(name))
+                }
+                (["anchor", name, "{"], None) if name.contains(Manager::PROFILE_PREFIX) => {
+                    let profile = rules
+                        .profile_destinations
+                        .entry(name.trim_matches('"').to_string())
+                        .or_default();
+                    for line in lines.by_ref().map(|s| s.trim()) {
+                        if line == "}" {
+                            break;
+                        }
+                        profile.extend(parse_list(line));
+                    }
+                }
+                _ => rules.extra_rules.push(line.into()),
+            }
+        }
+        if let Some(destinations) = tables.remove(&rules.block_table_name) {
+            rules.block_destinations.extend(destinations);
+        }
+        for (name, line) in overload_blocks {
+            if rules
+                .inbound_protection
+                .as_ref()
+                .is_none_or(|p| p.overload_table_name != name)
+            {
+                rules.extra_rules.push(line.into());
+            }
+        }
+        let merge = |ins: Vec<String>, outs: Vec<String>| -> HashSet<Direction> {
+            let mut directions = HashSet::new();
+            for s in &ins {
+                directions.insert(if outs.contains(s) {
+                    Direction::new(s)
+                } else {
+                    Direction::new(s).to_in()
+                });
+            }
+            for s in outs.iter().filter(|s| !ins.contains(s)) {
+                directions.insert(Direction::new(s).to_out());
+            }
+            directions
+        };
+        rules.pass_interfaces = merge(in_interfaces, out_interfaces);
+        if let Some(name) = in_table {
+            rules.in_table_name = name;
+        }
+        if let Some(name) = out_table {
+            rules.out_table_name = name;
+        }
+        for (interface, name) in interface_tables {
+            rules
+                .interface_destinations
+                .entry(interface)
+                .or_default()
+                .extend(tables.remove(&name).unwrap_or_default());
+        }
+        rules.pass_destinations = merge(
+            tables.remove(&rules.in_table_name).unwrap_or_default(),
+            tables.remove(&rules.out_table_name).unwrap_or_default(),
+        );
+        rules
+    }
+
+    fn get_writers() -> Vec<RulesWriter> {
+        let mut writers: Vec<RulesWriter> = vec![
+            |r, to| r.write_header(to),
+            |r, to| r.write_options(to),
+            |r, to| r.write_scrub(to),
+            |r, to| r.write_match(to),
+            |r, to| r.write_incoming(to),
+            |r, to| r.write_outgoing(to),
+            |r, to| r.write_antispoofing(to),
+            |r, to| r.write_blocklist(to),
+            |r, to| r.write_interfaces(to),
+            |r, to| r.write_dhcp(to),
+            |r, to| r.write_isolation(to),
+            |r, to| r.write_owners(to),
+            |r, to| r.write_ipv6(to),
+            |r, to| r.write_ipv4(to),
+            |r, to| r.write_tos(to),
+            |r, to| r.write_dns(to),
+            |r, to| r.write_ports(to),
+            |r, to| r.write_self(to),
+            |r, to| r.write_lan(to),
+        ];
+        #[cfg(target_os = "macos")]
+        writers.push(|r, to| r.write_continuity(to));
+        writers.extend_from_slice(&[
+            |r, to| r.write_icmp(to),
+            |r, to| r.write_destinations(to),
+            |r, to| r.write_profiles(to),
+            |r, to| r.write_extra_rules(to),
+        ]);
+        writers
+    }
+
+    fn write_macros(
+        &self,
+        mut to: impl IoWrite,
+        prefix: &str,
+        interfaces: impl IntoIterator<Item = impl AsRef<str>>,
+    ) -> IoResult<Vec<String>> {
+        let mut interfaces = interfaces
+            .into_iter()
+            .map(|s| s.as_ref().to_string())
+            .collect::<Vec<_>>();
+        interfaces.sort_unstable();
+        let mut macros = vec![];
+        for (idx, interface) in interfaces.iter().enumerate() {
+            let macro_var = &format!("{}{}_if", prefix, &idx);
+            writeln!(&mut to, "{} = \"{}\"", macro_var, interface)?;
+            macros.push(format!("${}", macro_var));
+        }
+        Ok(macros)
+    }
+
+    // addresses of `destination` with a `# host: ips` comment, as is if not resolved
+    fn resolve_hostname(&self, mut to: impl IoWrite, destination: &str) -> IoResult<Vec<String>> {
+        if !self.is_resolve_hostnames || is_address(destination) {
+            return Ok(vec![destination.to_string()]);
+        }
+        let mut ips = match (destination, 0).to_socket_addrs() {
+            Ok(resolved) => resolved.map(|a| a.ip().to_string()).collect::<Vec<_>>(),
+            Err(_) => vec![],
+        };
+        if ips.is_empty() {
+            return Ok(vec![destination.to_string()]);
+        }
+        ips.sort();
+        ips.dedup();
+        writeln!(&mut to, "# {}: {}", destination, ips.join(", "))?;
+        Ok(ips)
+    }
+
+    fn write_table(
+        &self,
+        mut to: impl IoWrite,
+        table_name: &str,
+        destinations: impl IntoIterator<Item = impl AsRef<str>>,
+    ) -> IoResult<()> {
+        Self::check_table_name(table_name)?;
+        let mut destinations = destinations
+            .into_iter()
+            .map(|s| s.as_ref().to_string())
+            .collect::<Vec<_>>();
+        destinations.sort_unstable();
+        destinations.dedup();
+        let mut addresses = vec![];
+        let mut files = vec![];
+        for destination in &destinations {
+            let destination = destination.as_str();
+            if destination.starts_with(&['/', '~', '$'][..]) {
+                let path = Path::new(destination).expandvars().expanduser();
+                if !path.is_file() {
+                    return Err(io::Error::new(
+                        ErrorKind::NotFound,
+                        format!("Destination file not found: `{}`", path.display()),
+                    ));
+                }
+                files.push(format!("file \"{}\"", path.display()));
+            } else {
+                addresses.extend(self.resolve_hostname(&mut to, destination)?);
+            }
+        }
+        writeln!(
+            &mut to,
+            "table <{}> {{ {} }} {}",
+            table_name,
+            addresses.join(", "),
+            files.join(" "),
+        )?;
+        Ok(())
+    }
+
+    // every `pass out` has to be bound to an interface, lan and icmp passes are
+    // directionless and already limited to local or control traffic
+    fn check_egress(rules: &str) -> IoResult<()> {
+        for line in rules.lines().map(|s| s.trim()) {
+            let words = line.split_whitespace().collect::<Vec<_>>();
+            if words.first() != Some(&"pass") || words.get(1) != Some(&"out") {
+                continue;
+            }
+            if !words.contains(&"on") && !words.contains(&"route-to") {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Strict egress: `{}` is not bound to an interface", line),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn check_interface(interface: &str) -> IoResult<()> {
+        if is_address(interface) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid interface: `{}` looks like an address", interface),
+            ));
+        }
+        Ok(())
+    }
+
+    fn check_table_name(table_name: &str) -> IoResult<()> {
+        if table_name.len() > Self::MAX_TABLE_NAME_LEN {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Invalid table name: `{}` (max: {})",
+                    table_name,
+                    Self::MAX_TABLE_NAME_LEN,
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    // pf tag names are at most 63 bytes
+    fn check_tag(tag: &str) -> IoResult<()> {
+        if tag.is_empty()
+            || tag.len() > 63
+            || !tag
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid tag: `{}`", tag),
+            ));
+        }
+        Ok(())
+    }
+
+    fn get_tag(&self) -> IoResult<String> {
+        match &self.tag {
+            Some(tag) => {
+                Self::check_tag(tag)?;
+                Ok(format!(" tag {}", tag))
+            }
+            None => Ok(String::new()),
+        }
+    }
+
+    fn get_quick(&self) -> &str {
+        if self.is_quick {
+            " quick"
+        } else {
+            ""
+        }
+    }
+
+    fn get_state(&self) -> String {
+        match self.pass_state {
+            PassState::Keep => String::new(),
+            PassState::No => format!(" {}", &self.pass_state),
+        }
+    }
+
+    fn get_log(&self, section: LogSections) -> String {
+        match &self.log_device {
+            Some(device) if self.log.contains(section) => format!("log (to {})", device),
+            None if self.log.contains(section) => "log".into(),
+            _ => String::new(),
+        }
+    }
+}
+
+impl Default for Rules {
+    fn default() -> Self {
+        Self {
+            block_table_name: Self::DEFAULT_BLOCK_TABLE_NAME.into(),
+            in_table_name: Self::DEFAULT_IN_TABLE_NAME.into(),
+            out_table_name: Self::DEFAULT_OUT_TABLE_NAME.into(),
+            platform: Default::default(),
+            timestamp: Default::default(),
+            block_policy_in: BlockPolicy::Drop,
+            block_policy_out: BlockPolicy::Return,
+            state_policy: Default::default(),
+            limits: Default::default(),
+            timeouts: Default::default(),
+            scrub: Default::default(),
+            min_ttl: 0,
+            log: Default::default(),
+            log_device: None,
+            loginterface: None,
+            incoming: Default::default(),
+            outgoing: Default::default(),
+            antispoofing: Some(Default::default()),
+            is_block_ipv6: false,
+            block_ipv6: Default::default(),
+            is_block_ipv4: false,
+            is_block_bogons: false,
+            is_resolve_hostnames: false,
+            dns_gateway: None,
+            dns_servers: Default::default(),
+            pass_out_ports: Default::default(),
+            self_addresses: Default::default(),
+            #[cfg(target_os = "macos")]
+            is_pass_continuity: false,
+            lan: Some(Default::default()),
+            is_isolate_lan: false,
+            is_pass_dhcp: false,
+            icmp: Some(Default::default()),
+            is_pass_icmp_errors: false,
+            skip_interfaces: Default::default(),
+            pass_interfaces: Default::default(),
+            pass_owners: Default::default(),
+            pass_state: Default::default(),
+            tos_rules: Default::default(),
+            block_destinations: Default::default(),
+            pass_destinations: Default::default(),
+            interface_destinations: Default::default(),
+            pass_remotes: Default::default(),
+            inbound_protection: None,
+            route_to: None,
+            is_strict_egress: false,
+            tag: None,
+            is_quick: true,
+            profile_destinations: Default::default(),
+            match_rules: Default::default(),
+            extra_rules: Default::default(),
+        }
+    }
+}
+
+// chained setters over `Rules::default()`, validation still happens on `Rules::build`
+#[derive(Default)]
+pub struct RulesBuilder {
+    rules: Rules,
+}
+
+impl RulesBuilder {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn table_names<S: Into<String>>(
+        mut self,
+        block_table_name: S,
+        in_table_name: S,
+        out_table_name: S,
+    ) -> Self {
+        self.rules
+            .set_table_names(block_table_name, in_table_name, out_table_name);
+        self
+    }
+
+    pub fn platform(mut self, platform: Platform) -> Self {
+        self.rules.platform = platform;
+        self
+    }
+
+    pub fn timestamp(mut self, timestamp: Timestamp) -> Self {
+        self.rules.timestamp = timestamp;
+        self
+    }
+
+    pub fn block_policy(mut self, block_policy: BlockPolicy) -> Self {
+        self.rules.set_block_policy(block_policy);
+        self
+    }
+
+    pub fn block_policy_in(mut self, block_policy: BlockPolicy) -> Self {
+        self.rules.block_policy_in = block_policy;
+        self
+    }
+
+    pub fn block_policy_out(mut self, block_policy: BlockPolicy) -> Self {
+        self.rules.block_policy_out = block_policy;
+        self
+    }
+
+    pub fn state_policy(mut self, state_policy: StatePolicy) -> Self {
+        self.rules.state_policy = state_policy;
+        self
+    }
+
+    pub fn limit(mut self, name: impl Into<String>, value: u32) -> Self {
+        self.rules.limits.insert(name.into(), value);
+        self
+    }
+
+    pub fn timeout(mut self, name: impl Into<String>, value: u32) -> Self {
+        self.rules.timeouts.insert(name.into(), value);
+        self
+    }
+
+    pub fn scrub(mut self, scrub: Scrub) -> Self {
+        self.rules.scrub = scrub;
+        self
+    }
+
+    pub fn min_ttl(mut self, min_ttl: u8) -> Self {
+        self.rules.min_ttl = min_ttl;
+        self
+    }
+
+    pub fn log(mut self, log: LogSections) -> Self {
+        self.rules.log = log;
+        self
+    }
+
+    pub fn log_device(mut self, device: impl Into<String>) -> Self {
+        self.rules.log_device = Some(device.into());
+        self
+    }
+
+    pub fn loginterface(mut self, interface: impl Into<String>) -> Self {
+        self.rules.loginterface = Some(interface.into());
+        self
+    }
+
+    pub fn incoming(mut self, action: Action) -> Self {
+        self.rules.incoming = action;
+        self
+    }
+
+    pub fn outgoing(mut self, action: Action) -> Self {
+        self.rules.outgoing = action;
+        self
+    }
+
+    pub fn antispoofing(mut self, antispoofing: Option<Antispoofing>) -> Self {
+        self.rules.antispoofing = antispoofing;
+        self
+    }
+
+    pub fn block_ipv6(mut self, is_block_ipv6: bool) -> Self {
+        self.rules.is_block_ipv6 = is_block_ipv6;
+        self
+    }
+
+    pub fn block_ipv4(mut self, is_block_ipv4: bool) -> Self {
+        self.rules.is_block_ipv4 = is_block_ipv4;
+        self
+    }
+
+    pub fn block_bogons(mut self, is_block_bogons: bool) -> Self {
+        self.rules.is_block_bogons = is_block_bogons;
+        self
+    }
+
+    pub fn resolve_hostnames(mut self, is_resolve_hostnames: bool) -> Self {
+        self.rules.is_resolve_hostnames = is_resolve_hostnames;
+        self
+    }
+
+    pub fn dns_gateway(mut self, gateway: impl Into<String>) -> Self {
+        self.rules.dns_gateway = Some(gateway.into());
+        self
+    }
+
+    #[cfg(target_os = "macos")]
+    pub fn pass_continuity(mut self, is_pass_continuity: bool) -> Self {
+        self.rules.is_pass_continuity = is_pass_continuity;
+        self
+    }
+
+    pub fn lan(mut self, lan: Option<Lan>) -> Self {
+        self.rules.lan = lan;
+        self
+    }
+
+    pub fn isolate_lan(mut self, is_isolate_lan: bool) -> Self {
+        self.rules.is_isolate_lan = is_isolate_lan;
+        self
+    }
+
+    pub fn pass_dhcp(mut self, is_pass_dhcp: bool) -> Self {
+        self.rules.is_pass_dhcp = is_pass_dhcp;
+        self
+    }
+
+    pub fn icmp(mut self, icmp: Option<ICMP>) -> Self {
+        self.rules.icmp = icmp;
+        self
+    }
+
+    pub fn pass_icmp_errors(mut self, is_pass_icmp_errors: bool) -> Self {
+        self.rules.is_pass_icmp_errors = is_pass_icmp_errors;
+        self
+    }
+
+    pub fn pass_state(mut self, pass_state: PassState) -> Self {
+        self.rules.pass_state = pass_state;
+        self
+    }
+
+    pub fn inbound_protection(mut self, protection: Option<InboundProtection>) -> Self {
+        self.rules.inbound_protection = protection;
+        self
+    }
+
+    pub fn route_to(mut self, interface: impl Into<String>, gateway: impl Into<String>) -> Self {
+        self.rules.route_to = Some((interface.into(), gateway.into()));
+        self
+    }
+
+    pub fn strict_egress(mut self, is_strict_egress: bool) -> Self {
+        self.rules.is_strict_egress = is_strict_egress;
+        self
+    }
+
+    pub fn tag(mut self, tag: impl Into<String>) -> Self {
+        self.rules.tag = Some(tag.into());
+        self
+    }
+
+    pub fn quick(mut self, is_quick: bool) -> Self {
+        self.rules.is_quick = is_quick;
+        self
+    }
+
+    pub fn add_dns_server(mut self, server: impl Into<String>) -> Self {
+        self.rules.dns_servers.push(server.into());
+        self
+    }
+
+    pub fn add_pass_out_port(mut self, port: PortSpec) -> Self {
+        self.rules.pass_out_ports.push(port);
+        self
+    }
+
+    pub fn add_self_address(mut self, address: impl Into<String>) -> Self {
+        self.rules.self_addresses.insert(address.into());
+        self
+    }
+
+    pub fn add_skip_interface(mut self, interface: impl Into<String>) -> Self {
+        self.rules.skip_interfaces.insert(interface.into());
+        self
+    }
+
+    // `in:` / `out:` prefix for one direction
+    pub fn add_pass_interface(mut self, interface: impl Into<Direction>) -> Self {
+        self.rules.pass_interfaces.insert(interface.into());
+        self
+    }
+
+    pub fn add_pass_owner(mut self, owner: impl Into<Owner>) -> Self {
+        self.rules.pass_owners.insert(owner.into());
+        self
+    }
+
+    pub fn add_tos_rule(mut self, rule: TosRule) -> Self {
+        self.rules.tos_rules.push(rule);
+        self
+    }
+
+    pub fn add_block_destination(mut self, destination: impl Into<String>) -> Self {
+        self.rules.block_destinations.insert(destination.into());
+        self
+    }
+
+    pub fn add_pass_destination(mut self, destination: impl Into<Direction>) -> Self {
+        self.rules.pass_destinations.insert(destination.into());
+        self
+    }
+
+    pub fn add_interface_destination(
+        mut self,
+        interface: impl Into<String>,
+        destination: impl Into<String>,
+    ) -> Self {
+        self.rules
+            .interface_destinations
+            .entry(interface.into())
+            .or_default()
+            .insert(destination.into());
+        self
+    }
+
+    pub fn add_pass_remote(mut self, remote: Remote) -> Self {
+        self.rules.pass_remotes.insert(remote);
+        self
+    }
+
+    pub fn add_profile_destination(
+        mut self,
+        profile: impl Into<String>,
+        destination: impl Into<String>,
+    ) -> Self {
+        self.rules
+            .profile_destinations
+            .entry(profile.into())
+            .or_default()
+            .insert(destination.into());
+        self
+    }
+
+    pub fn add_match_rule(mut self, rule: MatchRule) -> Self {
+        self.rules.match_rules.push(rule);
+        self
+    }
+
+    pub fn add_extra_rule(mut self, rule: impl Into<String>) -> Self {
+        self.rules.extra_rules.push(rule.into());
+        self
+    }
+
+    pub fn build(self) -> Rules {
+        self.rules
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn builder() -> RulesBuilder {
+        Rules::builder().timestamp(Timestamp::Omit)
+    }
+
+    fn position(conf: &str, line: &str) -> usize {
+        conf.find(line)
+            .unwrap_or_else(|| panic!("`{}` not in:\n{}", line, conf))
+    }
+
+    // a pfctl stub logging its arguments, `-s rules -a x` prints the file `-s_rules_-a_x`
+    // and `-s_rules_-a_x.err` to stderr
+    fn stub_ctl(name: &str) -> (Ctl, PathBuf) {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = temp_dir().join(format!("netlock-test-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        create_dir_all(&dir).unwrap();
+        let ctl_path = dir.join("pfctl");
+        let script = format!(
+            r#"#!/bin/sh
+dir="{}"
+echo "$*" >> "$dir/log"
+case "$*" in *"-f -"*) cat > /dev/null ;; esac
+out="$dir/$(echo "$*" | tr ' /' '__')"
+if [ -f "$out" ]; then cat "$out"; fi
+if [ -f "$out.err" ]; then cat "$out.err" >&2; fi
+"#,
+            dir.display(),
+        );
+        std::fs::write(&ctl_path, script).unwrap();
+        std::fs::set_permissions(&ctl_path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::fs::write(dir.join("pf.conf"), "").unwrap();
+        (Ctl::new(ctl_path, dir.join("pf.conf")), dir)
+    }
+
+    fn stub_output(dir: &Path, args: &str, output: &str) {
+        std::fs::write(dir.join(args.replace([' ', '/'], "_")), output).unwrap();
+    }
+
+    fn stub_stderr(dir: &Path, args: &str, output: &str) {
+        stub_output(dir, &format!("{}.err", args), output);
+    }
+
+    fn stub_log(dir: &Path) -> String {
+        read_to_string(dir.join("log")).unwrap_or_default()
+    }
+
+    #[test]
+    fn tos_is_written_after_ip_blocks() {
+        let mut rule = TosRule::from_dscp(TosRule::DSCP_EF);
+        rule.interface = Some("utun3".into());
+        let conf = builder()
+            .block_ipv6(true)
+            .block_ipv4(true)
+            .add_tos_rule(rule)
+            .build()
+            .build()
+            .unwrap();
+        let tos = position(&conf, "pass out quick on utun3 all tos 0xb8");
+        assert!(position(&conf, "block return out quick inet6 all") < tos);
+        assert!(position(&conf, "block return out quick inet all") < tos);
+    }
+
+    #[test]
+    fn remote_hostnames_are_resolved() {
+        let conf = builder()
+            .resolve_hostnames(true)
+            .add_pass_remote(Remote::new("localhost", Some(1194), Some(Remote::UDP)))
+            .build()
+            .build()
+            .unwrap();
+        assert!(!conf.contains("to localhost"), "{}", conf);
+        assert!(conf.contains("# localhost: "), "{}", conf);
+        assert!(conf.contains("pass out quick proto udp from any to 127.0.0.1 port 1194"));
+    }
+
+    #[test]
+    fn strict_egress_accepts_bound_passes() {
+        let rules = builder()
+            .strict_egress(true)
+            .icmp(Some(ICMP::echoreq()))
+            .add_pass_interface("utun3")
+            .build();
+        assert!(rules.build().is_ok());
+    }
+
+    #[test]
+    fn strict_egress_rejects_unbound_out_pass() {
+        let rules = builder()
+            .strict_egress(true)
+            .add_pass_destination("1.1.1.1")
+            .build();
+        assert_eq!(rules.build().unwrap_err().kind(), ErrorKind::InvalidInput);
+        let rules = builder().add_pass_destination("1.1.1.1").build();
+        assert!(rules.build().is_ok());
+    }
+
+    #[test]
+    fn tos_without_interface_is_rejected() {
+        let rules = builder()
+            .add_tos_rule(TosRule::from_dscp(TosRule::DSCP_EF))
+            .build();
+        assert_eq!(rules.build().unwrap_err().kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn long_table_names_are_rejected() {
+        let mut rules = builder()
+            .add_interface_destination("utun10", "1.1.1.1")
+            .build();
+        assert!(rules.build().is_ok());
+        rules.set_table_names("a_block", "a_pass_in", "abcdefghijklmnopqrstuv_pass_out");
+        assert_eq!(rules.build().unwrap_err().kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn history_stems_sort_numerically() {
+        let mut stems = [
+            "1700000001-0",
+            "1700000000-10",
+            "1700000000",
+            "1700000000-2",
+        ]
+        .iter()
+        .map(|s| Loader::parse_history_stem(s).unwrap())
+        .collect::<Vec<_>>();
+        stems.sort_unstable();
+        assert_eq!(
+            stems,
+            [
+                (1700000000, 0),
+                (1700000000, 2),
+                (1700000000, 10),
+                (1700000001, 0)
+            ],
+        );
+        assert_eq!(Loader::parse_history_stem("backup"), None);
+        assert_eq!(Loader::parse_history_stem("1700000000-x"), None);
+    }
+
+    #[test]
+    fn dhcp_passes_only_broadcast_and_local_subnets() {
+        let conf = builder().pass_dhcp(true).build().build().unwrap();
+        assert!(!conf.contains("to any port bootps"), "{}", conf);
+        position(
+            &conf,
+            "to { 255.255.255.255, 169.254.0.0/16, 192.168.0.0/16, 172.16.0.0/12, 10.0.0.0/8 } port bootps",
+        );
+        let lan = Lan {
+            subnets: HashSet::from(["192.168.1.0/24".to_string()]),
+            ..Default::default()
+        };
+        let conf = builder()
+            .pass_dhcp(true)
+            .lan(Some(lan))
+            .build()
+            .build()
+            .unwrap();
+        position(&conf, "to { 255.255.255.255, 192.168.1.0/24 } port bootps");
+    }
+
+    #[test]
+    fn ephemeral_enable_writes_no_files() {
+        let (ctl, dir) = stub_ctl("ephemeral");
+        let mut loader = Loader::ephemeral(Manager::new(ctl, builder().build()));
+        loader.enable(None::<&str>).unwrap();
+        assert!(stub_log(&dir).contains("-f -"));
+        let mut files = read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect::<Vec<_>>();
+        files.sort_unstable();
+        assert_eq!(files, ["log", "pf.conf", "pfctl"]);
+        assert!(!loader.get_firewall_conf_path().exists());
+        assert!(!loader.get_settings_conf_path().exists());
+        assert!(loader.history().unwrap().is_empty());
+    }
+
+    #[test]
+    fn bogons_are_added_to_block_table() {
+        let conf = builder().block_bogons(true).build().build().unwrap();
+        let table = conf
+            .lines()
+            .find(|line| line.starts_with("table <netlock_block>"))
+            .unwrap_or_else(|| panic!("no block table in:\n{}", conf));
+        for network in gvars::IPV4_BOGON_NETWORKS
+            .iter()
+            .chain(&gvars::IPV6_BOGON_NETWORKS)
+        {
+            assert!(table.contains(network), "{}", table);
+        }
+        position(&conf, "block return out quick from any to <netlock_block>");
+        let conf = builder()
+            .block_bogons(true)
+            .block_ipv6(true)
+            .build()
+            .build()
+            .unwrap();
+        assert!(conf.contains("100.64.0.0/10"), "{}", conf);
+        assert!(!conf.contains("2001:db8::/32"), "{}", conf);
+    }
+
+    #[test]
+    fn destination_file_paths_are_expanded() {
+        let table = |destination: &str| {
+            let conf = builder()
+                .add_block_destination(destination)
+                .build()
+                .build()?;
+            Ok::<_, io::Error>(
+                conf.lines()
+                    .find(|line| line.starts_with("table <netlock_block>"))
+                    .unwrap_or_else(|| panic!("no block table in:\n{}", conf))
+                    .to_string(),
+            )
+        };
+        let dir = temp_dir().join(format!("netlock-test-{}-lists", std::process::id()));
+        create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("list"), "192.0.2.1\n").unwrap();
+        std::env::set_var("NETLOCK_TEST_LISTS", &dir);
+        assert_eq!(
+            table("$NETLOCK_TEST_LISTS/list").unwrap(),
+            format!(
+                "table <netlock_block> {{  }} file \"{}\"",
+                dir.join("list").display()
+            ),
+        );
+        assert_eq!(
+            table("${NETLOCK_TEST_LISTS}/missing").unwrap_err().kind(),
+            ErrorKind::NotFound,
+        );
+        let name = format!(".netlock-test-{}-list", std::process::id());
+        let path = Path::new("~").expanduser().join(&name);
+        std::fs::write(&path, "192.0.2.1\n").unwrap();
+        let result = table(&format!("~/{}", name));
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            result.unwrap(),
+            format!("table <netlock_block> {{  }} file \"{}\"", path.display()),
+        );
+        assert_eq!(
+            table(&format!("~/{}", name)).unwrap_err().kind(),
+            ErrorKind::NotFound,
+        );
+        assert!(table("192.0.2.1").unwrap().contains("{ 192.0.2.1 }"));
+    }
+
+    #[test]
+    fn load_returns_stderr_as_warnings() {
+        let (ctl, dir) = stub_ctl("load_warnings");
+        let conf_path = dir.join("pf.conf");
+        stub_stderr(
+            &dir,
+            &format!("-f {}", conf_path.display()),
+            "No ALTQ support in kernel\n\nALTQ related functions disabled\n",
+        );
+        let warnings = ctl.load(LoadFile::Path(&conf_path), "").unwrap();
+        assert_eq!(
+            warnings,
+            [
+                "No ALTQ support in kernel",
+                "ALTQ related functions disabled"
+            ],
+        );
+        stub_stderr(
+            &dir,
+            "-f - -a netlock",
+            "  rule expands to no valid combination\n",
+        );
+        let warnings = ctl.load(LoadFile::Stdin("pass all\n"), "netlock").unwrap();
+        assert_eq!(warnings, ["rule expands to no valid combination"]);
+        let warnings = ctl.load(LoadFile::Stdin("pass all\n"), "").unwrap();
+        assert!(warnings.is_empty(), "{:?}", warnings);
+    }
+
+    #[test]
+    fn configuration_files_are_summarized() {
+        let (ctl, dir) = stub_ctl("configuration_files");
+        let profiles = dir.join("profiles");
+        create_dir_all(&profiles).unwrap();
+        std::fs::write(
+            profiles.join("a.ovpn"),
+            "remote 1.1.1.1 1194 udp\nremote 2.2.2.2 443 tcp\n",
+        )
+        .unwrap();
+        std::fs::write(
+            profiles.join("b.conf"),
+            "[Peer]\nEndpoint = 3.3.3.3:51820\n",
+        )
+        .unwrap();
+        let mut manager = Manager::new(ctl, builder().build());
+        let summary = manager
+            .extend_rules_from_configuration_files(&[&profiles])
+            .unwrap();
+        assert_eq!(summary.files_scanned(), 2);
+        assert_eq!(summary.destinations_added(), 3);
+        let count = |name: &str| {
+            summary
+                .files()
+                .iter()
+                .find(|(path, _)| path.ends_with(name))
+                .map(|(_, destinations)| destinations.len())
+        };
+        assert_eq!(count("a.ovpn"), Some(2));
+        assert_eq!(count("b.conf"), Some(1));
+        let summary = manager
+            .extend_rules_from_configuration_files(&[&profiles])
+            .unwrap();
+        assert_eq!(summary.destinations_added(), 0);
+    }
+
+    #[test]
+    fn flush_table_runs_table_flush_in_anchor() {
+        let (ctl, dir) = stub_ctl("flush_table");
+        let mut manager = Manager::new(ctl, builder().build());
+        manager.set_anchor("$/test");
+        manager.flush_table("netlock_block").unwrap();
+        assert_eq!(
+            stub_log(&dir),
+            "-t netlock_block -a 248.netlock/test -T flush\n"
+        );
+    }
+
+    #[test]
+    fn icmp_errors_pass_in_statelessly() {
+        let conf = builder().pass_icmp_errors(true).build().build().unwrap();
+        position(
+            &conf,
+            "pass in quick inet proto icmp all icmp-type { unreach, timex } no state",
+        );
+        position(
+            &conf,
+            "pass in quick inet6 proto icmp6 all icmp6-type { unreach, toobig, timex } no state",
+        );
+        let conf = builder()
+            .pass_icmp_errors(true)
+            .block_ipv6(true)
+            .build()
+            .build()
+            .unwrap();
+        assert!(!conf.contains("ICMP6_ERRORS"), "{}", conf);
+        let conf = builder().build().build().unwrap();
+        assert!(!conf.contains("ICMP_ERRORS"), "{}", conf);
+    }
+
+    fn stub_loader(name: &str) -> (Loader, PathBuf) {
+        let (ctl, dir) = stub_ctl(name);
+        let loader = Loader::new(dir.join("conf"), Manager::new(ctl, builder().build()));
+        (loader, dir)
+    }
+
+    fn stub_live_rules(dir: &Path) {
+        stub_output(dir, "-s info", "Status: Enabled for 0 days");
+        stub_output(
+            dir,
+            "-s rules",
+            "block drop out quick from any to <netlock_block>\n\
+             pass in quick from <netlock_pass_in> to any\n\
+             pass out quick from any to <netlock_pass_out>\n",
+        );
+        stub_output(
+            dir,
+            "-s Tables",
+            "netlock_block\nnetlock_pass_in\nnetlock_pass_out\n",
+        );
+    }
+
+    #[test]
+    fn reapply_loads_saved_rules_untouched() {
+        let (mut loader, dir) = stub_loader("reapply");
+        assert!(loader.reapply().is_err());
+        loader.enable(None::<&str>).unwrap();
+        stub_live_rules(&dir);
+        let conf_path = loader.get_firewall_conf_path();
+        let contents = read_to_string(&conf_path).unwrap();
+        let modified = conf_path.metadata().unwrap().modified().unwrap();
+        std::fs::remove_file(dir.join("log")).unwrap();
+        loader.reapply().unwrap();
+        assert_eq!(read_to_string(&conf_path).unwrap(), contents);
+        assert_eq!(conf_path.metadata().unwrap().modified().unwrap(), modified);
+        let log = stub_log(&dir);
+        assert!(
+            log.lines()
+                .any(|line| line == format!("-f {}", conf_path.display())),
+            "{}",
+            log
+        );
+        assert!(!log.contains("-f -"), "{}", log);
+        std::fs::remove_file(&conf_path).unwrap();
+        let err = loader.reapply().unwrap_err();
+        assert!(
+            err.to_string().contains("Firewall conf not found"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn consistency_with_live_rules() {
+        let (mut loader, dir) = stub_loader("consistency");
+        loader.enable(None::<&str>).unwrap();
+        stub_live_rules(&dir);
+        assert!(loader.check_consistency().unwrap().is_consistent());
+    }
+
+    #[test]
+    fn consistency_settings_on_live_off() {
+        let (mut loader, _) = stub_loader("consistency_on_off");
+        loader.enable(None::<&str>).unwrap();
+        let consistency = loader.check_consistency().unwrap();
+        assert!(consistency.drifts() == [Drift::FirewallDisabled, Drift::SettingsOnLiveOff]);
+    }
+
+    #[test]
+    fn consistency_settings_off_live_on() {
+        let (mut loader, dir) = stub_loader("consistency_off_on");
+        stub_live_rules(&dir);
+        let consistency = loader.check_consistency().unwrap();
+        assert!(consistency.drifts() == [Drift::SettingsOffLiveOn]);
+    }
+
+    #[test]
+    fn consistency_anchor_mismatch() {
+        let (mut loader, dir) = stub_loader("consistency_anchor");
+        loader.enable(Some("$/a")).unwrap();
+        stub_output(&dir, "-s info", "Status: Enabled for 0 days");
+        stub_output(&dir, "-s rules", "anchor \"248.netlock/b\" all\n");
+        stub_output(&dir, "-s Anchors -v", "  248.netlock/b\n");
+        stub_output(&dir, "-s rules -a 248.netlock/b", "block drop out all\n");
+        let consistency = loader.check_consistency().unwrap();
+        assert!(consistency.drifts() == [Drift::SettingsOnLiveOff, Drift::AnchorMismatch]);
+    }
+
+    fn stub_profiles(dir: &Path) -> PathBuf {
+        let profiles = dir.join("profiles");
+        create_dir_all(&profiles).unwrap();
+        std::fs::write(profiles.join("a.ovpn"), "remote 1.1.1.1 1194 udp\n").unwrap();
+        std::fs::write(
+            profiles.join("b.conf"),
+            "[Peer]\nEndpoint = 2.2.2.2:51820\n",
+        )
+        .unwrap();
+        profiles
+    }
+
+    #[test]
+    fn profiles_are_merged() {
+        let (ctl, dir) = stub_ctl("profiles_merged");
+        let profiles = stub_profiles(&dir);
+        let mut manager = Manager::new(ctl, builder().build());
+        manager
+            .extend_rules_from_configuration_files(&[&profiles])
+            .unwrap();
+        let conf = manager.rules().build().unwrap();
+        position(
+            &conf,
+            "pass out quick proto udp from any to 1.1.1.1 port 1194",
+        );
+        position(&conf, "to 2.2.2.2 port 51820");
+        assert!(!conf.contains("anchor \""), "{}", conf);
+    }
+
+    #[test]
+    fn profiles_get_own_anchors() {
+        let (ctl, dir) = stub_ctl("profiles_anchors");
+        let profiles = stub_profiles(&dir);
+        let mut manager = Manager::new(ctl, builder().build());
+        manager.profile_mode = ProfileMode::Anchors;
+        manager
+            .extend_rules_from_configuration_files(&[&profiles])
+            .unwrap();
+        let conf = manager.rules().build().unwrap();
+        let a = position(
+            &conf,
+            &format!("anchor \"{}a\" {{", Manager::PROFILE_PREFIX),
+        );
+        let b = position(
+            &conf,
+            &format!("anchor \"{}b\" {{", Manager::PROFILE_PREFIX),
+        );
+        assert!(a < position(&conf, "pass out quick from any to { 1.1.1.1 }"));
+        assert!(b < position(&conf, "pass out quick from any to { 2.2.2.2 }"));
+        assert!(!conf.contains("port 1194"), "{}", conf);
+    }
+
+    #[test]
+    fn log_only_outgoing_block() {
+        let conf = builder()
+            .log(LogSections::OUTGOING)
+            .antispoofing(Some(Default::default()))
+            .build()
+            .build()
+            .unwrap();
+        let blocks = conf
+            .lines()
+            .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+            .filter(|line| line.starts_with("block"))
+            .collect::<Vec<_>>();
+        assert!(blocks.contains(&"block drop in all".into()), "{:?}", blocks);
+        assert!(
+            blocks.contains(&"block return out log all".into()),
+            "{:?}",
+            blocks
+        );
+        assert_eq!(
+            blocks.iter().filter(|line| line.contains(" log")).count(),
+            1
+        );
+        let conf = builder().log(LogSections::ALL).build().build().unwrap();
+        position(&conf, "block drop in log all");
+    }
+
+    #[test]
+    fn pf_conf_round_trip() {
+        let conf = builder()
+            .block_policy_in(BlockPolicy::Return)
+            .min_ttl(64)
+            .limit("states", 20000)
+            .add_skip_interface("lo0")
+            .add_pass_interface("utun3")
+            .add_block_destination("192.0.2.1")
+            .add_pass_destination("198.51.100.1")
+            .add_extra_rule("pass out quick proto tcp to any port 22")
+            .build()
+            .build()
+            .unwrap();
+        let mut rules = Rules::from_pf_conf(&conf);
+        assert!(rules.block_policy_in == BlockPolicy::Return);
+        assert_eq!(rules.min_ttl, 64);
+        assert_eq!(rules.limits.get("states"), Some(&20000));
+        assert!(rules.skip_interfaces.contains("lo0"));
+        assert!(rules.pass_interfaces.contains(&Direction::new("utun3")));
+        assert!(rules.block_destinations.contains("192.0.2.1"));
+        assert!(rules
+            .pass_destinations
+            .contains(&Direction::new("198.51.100.1")));
+        assert_eq!(
+            rules.extra_rules,
+            ["pass out quick proto tcp to any port 22"]
+        );
+        rules.timestamp = Timestamp::Omit;
+        assert_eq!(rules.build().unwrap(), conf);
+    }
+
+    #[test]
+    fn routable_ipv6_block_keeps_local_passes() {
+        let mut rules = builder().block_ipv6(true).build();
+        rules.block_ipv6 = BlockIpv6::Routable;
+        let conf = rules.build().unwrap();
+        let block = position(&conf, "block return out quick inet6 all");
+        assert!(position(&conf, "pass quick inet6 from ::1 to ::1") < block);
+        assert!(
+            position(
+                &conf,
+                "pass quick inet6 from fe80::/10 to { fe80::/10, ff02::/16 }"
+            ) < block
+        );
+        let conf = builder().block_ipv6(true).build().build().unwrap();
+        position(&conf, "block return out quick inet6 all");
+        assert!(!conf.contains("IPV6_LOCAL"), "{}", conf);
+    }
+
+    #[test]
+    fn match_rule_sets_prio_before_filter_rules() {
+        let rule = MatchRule {
+            interface: Some(Direction::new("out:en0")),
+            prio: Some(6),
+            ..Default::default()
+        };
+        let conf = builder().add_match_rule(rule).build().build().unwrap();
+        let prio = position(&conf, "match out on en0 all set prio 6");
+        assert!(prio < position(&conf, "# INCOMING"));
+    }
+
+    #[test]
+    fn match_rule_prio_out_of_range_is_rejected() {
+        let rule = MatchRule {
+            destination: Some("192.0.2.1".into()),
+            prio: Some(MatchRule::MAX_PRIO + 1),
+            ..Default::default()
+        };
+        let rules = builder().add_match_rule(rule).build();
+        assert_eq!(rules.build().unwrap_err().kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn check_drift_compares_live_with_built_rules() {
+        let (ctl, dir) = stub_ctl("check_drift");
+        let manager = Manager::new(ctl, builder().build());
+        let rules = "block drop in all\nblock return out all\n";
+        stub_output(&dir, "-n -v -f -", rules);
+        stub_output(&dir, "-s rules", rules);
+        assert!(manager.check_drift().unwrap().is_empty());
+        stub_output(&dir, "-s rules", "block drop in all\npass out all\n");
+        let diff = manager.check_drift().unwrap();
+        assert!(diff.contains(&"- pass out all".into()), "{:?}", diff);
+        assert!(
+            diff.contains(&"+ block return out all".into()),
+            "{:?}",
+            diff
+        );
+        assert!(!stub_log(&dir).lines().any(|line| line.starts_with("-f")));
+    }
+
+    #[test]
+    fn ef_tos_rule_passes_udp() {
+        let mut rule = TosRule::from_dscp(TosRule::DSCP_EF);
+        rule.proto = Some("udp".into());
+        rule.interface = Some("utun3".into());
+        let conf = builder().add_tos_rule(rule).build().build().unwrap();
+        position(
+            &conf,
+            "pass out quick on utun3 proto udp all tos 0xb8 label \"TOS\"",
+        );
+        let conf = builder().build().build().unwrap();
+        assert!(!conf.contains(" tos "), "{}", conf);
+    }
+
+    #[test]
+    fn isolated_lan_is_blocked_except_dhcp() {
+        let conf = builder().isolate_lan(true).build().build().unwrap();
+        let block = position(
+            &conf,
+            "block return out quick inet from any to { 169.254.0.0/16, 192.168.0.0/16, 172.16.0.0/12, 10.0.0.0/8",
+        );
+        assert!(position(&conf, "port bootpc to { 255.255.255.255") < block);
+        assert!(position(&conf, "port dhcpv6-client") < block);
+        position(&conf, "block in quick inet from { 169.254.0.0/16");
+        position(
+            &conf,
+            "block return out quick inet6 from any to { fe80::/10, fc00::/7",
+        );
+        assert!(
+            !conf.contains("pass quick inet from 192.168.0.0/16"),
+            "{}",
+            conf
+        );
+    }
+
+    #[test]
+    fn status_anchors_leave_out_main_ruleset() {
+        let status = Status {
+            firewall_state: true,
+            netlock_state: true,
+            is_drifted: false,
+            rules: HashMap::from([
+                ("".to_string(), "anchor \"248.netlock\" all".to_string()),
+                ("248.netlock".to_string(), "block drop out all".to_string()),
+                ("com.apple".to_string(), "pass all".to_string()),
+            ]),
+            states: None,
+            interfaces: vec![],
+        };
+        assert_eq!(status.main_ruleset(), Some("anchor \"248.netlock\" all"));
+        let mut anchors = status.anchors().collect::<Vec<_>>();
+        anchors.sort_unstable();
+        assert_eq!(
+            anchors,
+            [
+                ("248.netlock", "block drop out all"),
+                ("com.apple", "pass all")
+            ],
+        );
+    }
+
+    #[test]
+    fn gateway_dns_is_passed() {
+        let info = crate::tools::parse_routing_table(
+            "\
+Routing tables
+
+Internet:
+Destination      Gateway          RT_IFA           Flags   Refs  Use   Mtu   Netif Expire
+0/1              10.8.0.1         10.8.0.5         UGScg   8     0     1500  utun4
+default          192.168.1.1      192.168.1.20     UGScg   12    0     1500  en0
+127              127.0.0.1        127.0.0.1        UCS     1     0     16384 lo0
+128.0/1          10.8.0.1         10.8.0.5         UGSc    4     0     1500  utun4
+198.51.100.7/32  192.168.1.1      192.168.1.20     UGSc    2     0     1500  en0
+",
+        );
+        assert_eq!(info.gateway(), "192.168.1.1");
+        let (ctl, _) = stub_ctl("gateway_dns");
+        let mut manager = Manager::new(ctl, builder().build());
+        manager.extend_rules_from_routing_info(&info);
+        assert!(!manager
+            .rules
+            .build()
+            .unwrap()
+            .contains("to 192.168.1.1 port domain"));
+        manager.is_pass_gateway_dns = true;
+        manager.extend_rules_from_routing_info(&info);
+        position(
+            &manager.rules.build().unwrap(),
+            "pass out quick inet proto { tcp, udp } from any to 192.168.1.1 port domain",
+        );
+        let conf = builder()
+            .dns_gateway("fe80::1%en0")
+            .build()
+            .build()
+            .unwrap();
+        position(
+            &conf,
+            "pass out quick inet6 proto { tcp, udp } from any to fe80::1%en0 port domain",
+        );
+    }
+
+    #[test]
+    fn addresses_are_rejected_as_interfaces() {
+        for interface in ["1.2.3.4", "10.0.0.0/8", "2001:db8::1"] {
+            let rules = builder().add_pass_interface(interface).build();
+            let err = rules.build().unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput, "{}", interface);
+            assert!(err.to_string().contains("looks like an address"), "{}", err);
+        }
+        assert!(builder()
+            .add_pass_interface("utun1")
+            .build()
+            .build()
+            .is_ok());
+    }
+
+    #[cfg(target_os = "macos")]
+    #[test]
+    fn continuity_passes_awdl_and_link_local() {
+        let link_local =
+            "pass quick inet6 from fe80::/10 to { fe80::/10, ff02::/16 } label \"CONTINUITY\"";
+        let conf = builder().pass_continuity(true).build().build().unwrap();
+        position(
+            &conf,
+            "pass quick on { awdl0, llw0 } all label \"CONTINUITY\"",
+        );
+        position(&conf, link_local);
+        let conf = builder()
+            .pass_continuity(true)
+            .block_ipv6(true)
+            .build()
+            .build()
+            .unwrap();
+        position(
+            &conf,
+            "pass quick on { awdl0, llw0 } all label \"CONTINUITY\"",
+        );
+        assert!(!conf.contains(link_local), "{}", conf);
+        let conf = builder().build().build().unwrap();
+        assert!(!conf.contains("CONTINUITY\""), "{}", conf);
+    }
+
+    #[test]
+    fn lan_passes_only_supplied_subnet() {
+        let lan = Lan {
+            subnets: HashSet::from(["192.168.1.0/24".to_string()]),
+            ..Default::default()
+        };
+        let conf = builder().lan(Some(lan)).build().build().unwrap();
+        position(
+            &conf,
+            "pass quick inet from 192.168.1.0/24 to { 192.168.1.0/24,",
+        );
+        for network in gvars::IPV4_PRIVATE_NETWORKS {
+            assert!(!conf.contains(&format!("from {} ", network)), "{}", conf);
+        }
+        let lan = Lan {
+            subnets: HashSet::from(["home".to_string()]),
+            ..Default::default()
+        };
+        let rules = builder().lan(Some(lan)).build();
+        assert_eq!(rules.build().unwrap_err().kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn self_addresses_are_passed() {
+        let conf = builder()
+            .add_self_address("192.168.1.20")
+            .add_self_address("2001:db8::20")
+            .build()
+            .build()
+            .unwrap();
+        position(
+            &conf,
+            "pass quick inet from 192.168.1.20 to 192.168.1.20 label \"SELF\"",
+        );
+        position(
+            &conf,
+            "pass quick inet6 from 2001:db8::20 to 2001:db8::20 label \"SELF\"",
+        );
+        let conf = builder().build().build().unwrap();
+        assert!(!conf.contains("\"SELF\""), "{}", conf);
+    }
+
+    #[test]
+    fn loopback_is_skipped_or_passed_per_platform() {
+        for platform in [Platform::Macos, Platform::FreeBsd, Platform::OpenBsd] {
+            for (mode, is_pass) in [(LoopbackMode::Skip, false), (LoopbackMode::Pass, true)] {
+                let (ctl, _) = stub_ctl("loopback");
+                let mut manager = Manager::new(ctl, builder().platform(platform).build());
+                manager.loopback_mode = mode;
+                manager.set_skipass_loopback().unwrap();
+                let conf = manager.rules().build().unwrap();
+                assert_eq!(
+                    conf.contains("set skip on { $skip0_if }"),
+                    !is_pass,
+                    "{}",
+                    conf
+                );
+                assert_eq!(
+                    conf.contains("pass in quick on { $pass_in0_if } all"),
+                    is_pass
+                );
+                assert!(conf.contains(" = \"lo\""), "{}", conf);
+            }
+        }
+    }
+
+    #[test]
+    fn custom_loopback_is_used() {
+        for (mode, line) in [
+            (LoopbackMode::Skip, "set skip on { $skip0_if }"),
+            (LoopbackMode::Pass, "pass in quick on { $pass_in0_if } all"),
+        ] {
+            let (ctl, _) = stub_ctl("loopback_lo0");
+            let mut manager = Manager::new(ctl, builder().build());
+            manager.loopback = "lo0".into();
+            manager.loopback_mode = mode;
+            manager.set_skipass_loopback().unwrap();
+            let conf = manager.rules().build().unwrap();
+            position(&conf, line);
+            assert!(conf.contains(" = \"lo0\""), "{}", conf);
+        }
+        // anchored rules can not `set skip`, interfaces of the group not skipped yet are passed
+        let (ctl, dir) = stub_ctl("loopback_lo0_anchor");
+        stub_output(&dir, "-s Interfaces -v -i lo0", "lo0\nlo1 (skip)\n");
+        let mut manager = Manager::new(ctl, builder().build());
+        manager.loopback = "lo0".into();
+        assert!(manager.set_anchor("test"));
+        manager.set_skipass_loopback().unwrap();
+        assert!(stub_log(&dir)
+            .lines()
+            .any(|line| line == "-s Interfaces -v -i lo0"));
+        let conf = manager.rules().build().unwrap();
+        assert!(!conf.contains("set skip"), "{}", conf);
+        assert!(conf.contains(" = \"lo0\""), "{}", conf);
+        assert!(!conf.contains("lo1"), "{}", conf);
+    }
+
+    #[test]
+    fn diagnostics_include_each_section() {
+        let (mut loader, dir) = stub_loader("diagnostics");
+        stub_output(&dir, "-s info", "Status: Enabled for 0 days");
+        stub_output(&dir, "-s rules -v", "pass out quick from any to 192.0.2.1");
+        stub_output(&dir, "-s Anchors -v", "  com.apple");
+        let diagnostics = loader.diagnostics(false);
+        let names = diagnostics
+            .sections()
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                "INFO",
+                "RULES",
+                "ANCHORS",
+                "INTERFACES",
+                "ROUTING",
+                "SETTINGS"
+            ],
+        );
+        assert_eq!(
+            diagnostics.section("INFO"),
+            Some("Status: Enabled for 0 days")
+        );
+        assert_eq!(diagnostics.section("ANCHORS"), Some("  com.apple"));
+        assert_eq!(
+            diagnostics.section("RULES"),
+            Some("pass out quick from any to 192.0.2.1"),
+        );
+        assert_eq!(
+            loader.diagnostics(true).section("RULES"),
+            Some("pass out quick from any to <redacted>"),
+        );
+    }
+
+    #[test]
+    fn route_to_is_added_to_out_table_pass() {
+        let conf = builder()
+            .route_to("en0", "192.168.1.1")
+            .add_pass_destination("198.51.100.1")
+            .build()
+            .build()
+            .unwrap();
+        position(
+            &conf,
+            "pass out quick route-to (en0 192.168.1.1) from any to <netlock_pass_out>",
+        );
+        for (interface, gateway) in [
+            ("en0", "gateway"),
+            ("", "192.168.1.1"),
+            ("10.0.0.1", "192.168.1.1"),
+        ] {
+            let rules = builder().route_to(interface, gateway).build();
+            assert_eq!(rules.build().unwrap_err().kind(), ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn settings_tolerate_crlf() {
+        let (mut loader, _) = stub_loader("settings_crlf");
+        create_dir_all(loader.conf_dir()).unwrap();
+        std::fs::write(
+            loader.get_settings_conf_path(),
+            "MANAGER_STATE: true\r\nMANAGER_ANCHOR:248.netlock/a \r\nEXPIRY: 1700000000\r\n",
+        )
+        .unwrap();
+        loader.load_settings_conf().unwrap();
+        assert!(loader.manager.state);
+        assert_eq!(loader.manager.anchor, "248.netlock/a");
+        assert_eq!(loader.expiry, Some(1_700_000_000));
+    }
+
+    #[test]
+    fn settings_keep_colons_in_values() {
+        let (mut loader, _) = stub_loader("settings_colons");
+        let backup_path = PathBuf::from("/backup/fe80::1%en0.conf");
+        loader.manager.restore_conf_path = Some(backup_path.clone());
+        loader.make_settings_conf().unwrap();
+        loader.manager.restore_conf_path = None;
+        loader.load_settings_conf().unwrap();
+        assert_eq!(loader.manager.restore_conf_path, Some(backup_path));
+    }
+
+    #[test]
+    fn pass_state_keep_and_no() {
+        let conf = builder()
+            .add_pass_destination("198.51.100.1")
+            .build()
+            .build()
+            .unwrap();
+        position(&conf, "pass out quick from any to <netlock_pass_out>\n");
+        assert!(!conf.contains("no state"), "{}", conf);
+        let conf = builder()
+            .pass_state(PassState::No)
+            .add_pass_destination("198.51.100.1")
+            .build()
+            .build()
+            .unwrap();
+        position(
+            &conf,
+            "pass in quick from <netlock_pass_in> to any no state\n",
+        );
+        position(
+            &conf,
+            "pass out quick from any to <netlock_pass_out> no state\n",
+        );
+    }
+
+    // (path, contents) below `dir`, one level of subdirectories as for `history`
+    fn snapshot(dir: &Path) -> Vec<(PathBuf, String)> {
+        let mut files = vec![];
+        for entry in read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                files.extend(snapshot(&path));
+            } else {
+                files.push((path.clone(), read_to_string(&path).unwrap()));
+            }
+        }
+        files.sort_unstable();
+        files
+    }
+
+    #[test]
+    fn dry_run_executes_and_writes_nothing() {
+        let (mut loader, dir) = stub_loader("dry_run");
+        loader.enable(None::<&str>).unwrap();
+        let files = snapshot(loader.conf_dir());
+        std::fs::remove_file(dir.join("log")).unwrap();
+        loader.manager().set_dry_run(true);
+        loader
+            .manager()
+            .rules()
+            .pass_destinations
+            .insert(Direction::new("198.51.100.1"));
+        loader.reload(None::<&str>).unwrap();
+        loader.enable(None::<&str>).unwrap();
+        loader.load(None::<&str>).unwrap();
+        loader.disable().unwrap();
+        assert!(snapshot(loader.conf_dir()) == files);
+        for line in stub_log(&dir).lines() {
+            assert!(
+                line.starts_with("-s ") || line.starts_with("-n "),
+                "{}",
+                line
+            );
         }
     }
 }
@@ -1,14 +1,18 @@
-use std::fs::read_dir;
-use std::io;
-use std::net::Ipv4Addr;
-use std::path::Path;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Display, Formatter};
+use std::fs::{read_dir, File};
+use std::io::{self, BufRead, BufReader, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
-use crate::utils::{exec, read_lines, ExecResult, IsHidden};
+use crate::gvars;
+use crate::utils::{self, exec, read_lines, time, ExecResult, IsHidden};
 
 pub struct RoutingInfo {
     interface: String,
     destination: String,
+    gateway: String,
 }
 
 impl RoutingInfo {
@@ -19,10 +23,22 @@ impl RoutingInfo {
     pub fn destination(&self) -> &str {
         &self.destination
     }
+
+    pub fn gateway(&self) -> &str {
+        &self.gateway
+    }
 }
 
 #[cfg(unix)]
 pub fn get_useful_routing_table_info() -> ExecResult<RoutingInfo> {
+    let output = exec("/usr/sbin/netstat", ["-lnr", "-f", "inet"])?;
+    Ok(parse_routing_table(&String::from_utf8_lossy(
+        &output.stdout,
+    )))
+}
+
+// `netstat -lnr -f inet` output
+pub fn parse_routing_table(table: &str) -> RoutingInfo {
     // TODO IPv6
     struct Record<'a> {
         destination: &'a str,
@@ -53,19 +69,18 @@ pub fn get_useful_routing_table_info() -> ExecResult<RoutingInfo> {
     let mut destination = String::new();
     let mut default_gateway = "";
     let mut default_netif = "";
-    for record in
-        String::from_utf8_lossy(&exec("/usr/sbin/netstat", &["-lnr", "-f", "inet"])?.stdout)
-            .lines()
-            .map(|s| s.split_whitespace().collect::<Vec<_>>())
-            .filter(|v| v.len() >= 8)
-            .skip(1) // header
-            .map(|v| Record {
-                destination: v[0],
-                gateway: v[1],
-                flags: v[3],
-                netif: v[7],
-            })
-            .filter(|r| !r.is_loopback() && r.check_flags())
+    for record in table
+        .lines()
+        .map(|s| s.split_whitespace().collect::<Vec<_>>())
+        .filter(|v| v.len() >= 8)
+        .skip(1) // header
+        .map(|v| Record {
+            destination: v[0],
+            gateway: v[1],
+            flags: v[3],
+            netif: v[7],
+        })
+        .filter(|r| !r.is_loopback() && r.check_flags())
     {
         if record.is_master() && interface.is_empty() {
             interface = record.netif.into();
@@ -96,58 +111,508 @@ pub fn get_useful_routing_table_info() -> ExecResult<RoutingInfo> {
             }
         }
     }
-    Ok(RoutingInfo {
+    let gateway = if default_gateway.parse::<IpAddr>().is_ok() {
+        default_gateway.into()
+    } else {
+        String::new()
+    };
+    RoutingInfo {
         interface,
         destination,
+        gateway,
+    }
+}
+
+#[cfg(unix)]
+pub fn get_routing_table() -> ExecResult<String> {
+    let output = exec("/usr/sbin/netstat", ["-nr"])?;
+    Ok(String::from_utf8_lossy(&output.stdout).into())
+}
+
+#[cfg(unix)]
+pub fn get_interfaces() -> ExecResult<String> {
+    let output = exec("/sbin/ifconfig", ["-a"])?;
+    Ok(String::from_utf8_lossy(&output.stdout).into())
+}
+
+// keeps only ip and cidr entries, comments and hostnames are dropped
+#[cfg(unix)]
+pub fn fetch_blocklist(url: &str) -> ExecResult<Vec<String>> {
+    let output = exec("/usr/bin/curl", ["-fsSL", "--max-time", "30", url])?;
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| line.split(['#', ';']).next()?.split_whitespace().next())
+        .filter(|s| is_address(s))
+        .filter_map(|s| normalize_destination(s).ok())
+        .collect())
+}
+
+// addresses with their prefix length, loopback interfaces excluded
+#[cfg(unix)]
+fn get_interface_addresses() -> ExecResult<Vec<(IpAddr, u32)>> {
+    Ok(parse_interface_addresses(&get_interfaces()?))
+}
+
+// `ifconfig` output, netmasks are hex (`0xffffff00`)
+fn parse_interface_addresses(output: &str) -> Vec<(IpAddr, u32)> {
+    let mut addresses = vec![];
+    let mut is_loopback = false;
+    for line in output.lines() {
+        if !line.starts_with(char::is_whitespace) {
+            is_loopback = line.starts_with("lo");
+            continue;
+        }
+        if is_loopback {
+            continue;
+        }
+        match line.split_whitespace().collect::<Vec<_>>()[..] {
+            ["inet", addr, "netmask", mask, ..] => {
+                if let (Ok(addr), Ok(mask)) = (
+                    addr.parse::<Ipv4Addr>(),
+                    u32::from_str_radix(mask.trim_start_matches("0x"), 16),
+                ) {
+                    addresses.push((addr.into(), mask.count_ones()));
+                }
+            }
+            ["inet6", addr, "prefixlen", prefixlen, ..] => {
+                let addr = addr.split('%').next().unwrap_or(addr);
+                if let (Ok(addr), Ok(prefixlen)) =
+                    (addr.parse::<Ipv6Addr>(), prefixlen.parse::<u32>())
+                {
+                    if prefixlen <= 128 {
+                        addresses.push((addr.into(), prefixlen));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    addresses
+}
+
+// private (and link-local) networks the host is directly attached to
+#[cfg(unix)]
+pub fn get_local_subnets() -> ExecResult<Vec<String>> {
+    let mut subnets = vec![];
+    for (addr, prefixlen) in get_interface_addresses()? {
+        let subnet = match addr {
+            IpAddr::V4(addr) if addr.is_private() || addr.is_link_local() => {
+                let mask = u32::MAX.checked_shl(32 - prefixlen).unwrap_or(0);
+                format!("{}/{}", Ipv4Addr::from(u32::from(addr) & mask), prefixlen)
+            }
+            IpAddr::V6(addr) if addr.is_unicast_link_local() => {
+                gvars::IPV6_LINK_LOCAL_NETWORK.into()
+            }
+            IpAddr::V6(addr) if addr.is_unique_local() => {
+                let mask = u128::MAX.checked_shl(128 - prefixlen).unwrap_or(0);
+                format!("{}/{}", Ipv6Addr::from(u128::from(addr) & mask), prefixlen)
+            }
+            _ => continue,
+        };
+        if !subnets.contains(&subnet) {
+            subnets.push(subnet);
+        }
+    }
+    Ok(subnets)
+}
+
+// link-local addresses are left out, they need a scope to be usable
+#[cfg(unix)]
+pub fn get_local_addresses() -> ExecResult<Vec<String>> {
+    Ok(get_interface_addresses()?
+        .into_iter()
+        .filter(|(addr, _)| match addr {
+            IpAddr::V4(addr) => !addr.is_link_local(),
+            IpAddr::V6(addr) => !addr.is_unicast_link_local(),
+        })
+        .map(|(addr, _)| addr.to_string())
+        .collect())
+}
+
+#[cfg(unix)]
+pub fn get_utc_offset() -> ExecResult<i64> {
+    let output = exec("/bin/date", ["+%z"])?;
+    let offset = String::from_utf8_lossy(&output.stdout);
+    let offset = offset.trim();
+    let (sign, hhmm) = match offset.split_at_checked(1) {
+        Some(("+", hhmm)) => (1, hhmm),
+        Some(("-", hhmm)) => (-1, hhmm),
+        _ => (0, ""),
+    };
+    match (hhmm.len(), hhmm.parse::<i64>()) {
+        (4, Ok(v)) => Ok(sign * ((v / 100) * 60 * 60 + (v % 100) * 60)),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid utc offset: `{}`", offset),
+        )
+        .into()),
+    }
+}
+
+pub fn parse_time(s: &str) -> ExecResult<u64> {
+    utils::parse_time(s, time(), get_utc_offset()?).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid time: `{}`", s),
+        )
+        .into()
     })
 }
 
-fn get_destinations_from_ovpn_file(path: impl AsRef<Path>) -> io::Result<Vec<String>> {
+#[derive(PartialEq, Eq, Hash, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Remote {
+    host: String,
+    port: Option<u16>,
+    proto: Option<String>,
+}
+
+impl<'a> Remote {
+    pub const UDP: &'a str = "udp";
+    pub const TCP: &'a str = "tcp";
+
+    pub fn new(host: impl Into<String>, port: Option<u16>, proto: Option<&str>) -> Self {
+        Self {
+            host: host.into(),
+            port,
+            proto: proto.and_then(Self::normalize_proto).map(|s| s.into()),
+        }
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    pub fn port(&self) -> Option<u16> {
+        self.port
+    }
+
+    pub fn proto(&self) -> Option<&str> {
+        self.proto.as_deref()
+    }
+
+    // openvpn spells them `udp4`, `tcp-client` and so on
+    fn normalize_proto(proto: &str) -> Option<&'a str> {
+        let proto = proto.to_lowercase();
+        if proto.starts_with(Self::UDP) {
+            Some(Self::UDP)
+        } else if proto.starts_with(Self::TCP) {
+            Some(Self::TCP)
+        } else {
+            None
+        }
+    }
+}
+
+impl Display for Remote {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", &self.host)?;
+        if let Some(port) = self.port {
+            write!(f, " port {}", port)?;
+        }
+        if let Some(proto) = &self.proto {
+            write!(f, " proto {}", proto)?;
+        }
+        Ok(())
+    }
+}
+
+pub type ConfigurationParser = fn(&Path) -> io::Result<Vec<Remote>>;
+
+pub struct ParserRegistry {
+    parsers: HashMap<String, ConfigurationParser>,
+    remote_policy: RemotePolicy,
+}
+
+impl<'a> ParserRegistry {
+    pub const OVPN_EXTENSION: &'a str = "ovpn";
+    pub const WIREGUARD_EXTENSION: &'a str = "conf";
+    pub const WG_EXTENSION: &'a str = "wg";
+    // shared by openvpn and wireguard, the content decides before the registered parser
+    const AMBIGUOUS_EXTENSIONS: [&'a str; 1] = [Self::WIREGUARD_EXTENSION];
+
+    pub fn new() -> Self {
+        Self {
+            parsers: HashMap::new(),
+            remote_policy: RemotePolicy::default(),
+        }
+    }
+
+    // applies to the ovpn extension and to files sniffed as openvpn
+    pub fn set_remote_policy(&mut self, policy: RemotePolicy) {
+        let parser: ConfigurationParser = match policy {
+            RemotePolicy::All => |path| get_destinations_from_ovpn_file(path, RemotePolicy::All),
+            RemotePolicy::First => {
+                |path| get_destinations_from_ovpn_file(path, RemotePolicy::First)
+            }
+        };
+        self.remote_policy = policy;
+        self.register(Self::OVPN_EXTENSION, parser);
+    }
+
+    pub fn register(&mut self, extension: impl Into<String>, parser: ConfigurationParser) {
+        self.parsers.insert(extension.into(), parser);
+    }
+
+    pub fn unregister(&mut self, extension: impl AsRef<str>) -> Option<ConfigurationParser> {
+        self.parsers.remove(extension.as_ref())
+    }
+
+    pub fn get(&self, extension: impl AsRef<str>) -> Option<ConfigurationParser> {
+        self.parsers.get(extension.as_ref()).copied()
+    }
+
+    pub fn extensions(&self) -> impl Iterator<Item = &str> {
+        self.parsers.keys().map(|s| s.as_str())
+    }
+
+    pub fn is_supported(&self, path: &Path) -> bool {
+        path.extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| self.parsers.contains_key(ext))
+    }
+}
+
+impl Default for ParserRegistry {
+    fn default() -> Self {
+        let mut registry = Self::new();
+        registry.set_remote_policy(RemotePolicy::All);
+        registry.register(
+            Self::WIREGUARD_EXTENSION,
+            get_destinations_from_wireguard_file,
+        );
+        registry.register(Self::WG_EXTENSION, get_destinations_from_wireguard_file);
+        registry
+    }
+}
+
+// `[2001:db8::1]` literals lose the brackets and have to be ipv6 addresses
+fn parse_remote_host(host: &str) -> io::Result<String> {
+    match host.strip_prefix('[') {
+        Some(s) => match s.strip_suffix(']').map(|s| s.parse::<Ipv6Addr>()) {
+            Some(Ok(address)) => Ok(address.to_string()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid remote: `{}`", host),
+            )),
+        },
+        None => Ok(host.to_string()),
+    }
+}
+
+// which `remote` lines of an ovpn file are passed, openvpn tries them in order unless
+// `remote-random` is set, then any of them may be used and `First` passes all too
+#[derive(Default, Clone, Copy, PartialEq, Eq)]
+pub enum RemotePolicy {
+    #[default]
+    All,
+    First,
+}
+
+// `port` and `proto` directives apply to remotes not setting their own
+pub fn get_destinations_from_ovpn_file(
+    path: &Path,
+    policy: RemotePolicy,
+) -> io::Result<Vec<Remote>> {
+    let mut remotes = vec![];
+    let mut default_port = None;
+    let mut default_proto = None;
+    let mut is_remote_random = false;
+    for line in read_lines(path)? {
+        let line = line?;
+        let line = line.trim_start();
+        if line.starts_with(&['#', ';'][..]) {
+            continue;
+        }
+        match line.split_whitespace().collect::<Vec<_>>()[..] {
+            ["remote", host, ref rest @ ..] => remotes.push((
+                parse_remote_host(host)?,
+                rest.first().and_then(|s| s.parse::<u16>().ok()),
+                rest.get(1).map(|s| s.to_string()),
+            )),
+            ["port", port, ..] => default_port = port.parse::<u16>().ok(),
+            ["proto", proto, ..] => default_proto = Some(proto.to_string()),
+            ["remote-random", ..] => is_remote_random = true,
+            _ => {}
+        }
+    }
+    if policy == RemotePolicy::First && !is_remote_random {
+        remotes.truncate(1);
+    }
+    Ok(remotes
+        .into_iter()
+        .map(|(host, port, proto)| {
+            Remote::new(
+                host,
+                port.or(default_port),
+                proto.or_else(|| default_proto.clone()).as_deref(),
+            )
+        })
+        .collect())
+}
+
+pub fn get_destinations_from_wireguard_file(path: &Path) -> io::Result<Vec<Remote>> {
     let mut destinations = vec![];
     for line in read_lines(path)? {
         let line = line?;
-        if !line.starts_with("remote ") {
+        let (key, value) = match line.split_once('=') {
+            Some((k, v)) => (k.trim(), v.trim()),
+            None => continue,
+        };
+        if !key.eq_ignore_ascii_case("endpoint") {
             continue;
         }
-        if let Some(destination) = line.split_whitespace().nth(1) {
-            destinations.push(destination.into());
+        if let Some((host, port)) = value.rsplit_once(':') {
+            destinations.push(Remote::new(
+                parse_remote_host(host)?,
+                port.parse().ok(),
+                Some(Remote::UDP),
+            ));
         }
     }
     Ok(destinations)
 }
 
-fn get_destinations_from_configuration_file(path: impl AsRef<Path>) -> io::Result<Vec<String>> {
-    let path = path.as_ref();
-    if let Some(ext) = path.extension() {
-        if ext == "ovpn" {
-            return get_destinations_from_ovpn_file(path);
+enum ConfigurationFormat {
+    Ovpn,
+    WireGuard,
+}
+
+// renamed configs are recognized by their first lines
+fn sniff_configuration_file(path: &Path) -> Option<ConfigurationFormat> {
+    let file = File::open(path).ok()?;
+    BufReader::new(file.take(gvars::CONFIGURATION_SNIFF_BYTES))
+        .lines()
+        .map_while(Result::ok)
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty() && !s.starts_with(&['#', ';'][..]))
+        .take(gvars::CONFIGURATION_SNIFF_LINES)
+        .find_map(|s| {
+            if s.eq_ignore_ascii_case("[Interface]") || s.eq_ignore_ascii_case("[Peer]") {
+                Some(ConfigurationFormat::WireGuard)
+            } else if s.split_whitespace().next() == Some("remote") {
+                Some(ConfigurationFormat::Ovpn)
+            } else {
+                None
+            }
+        })
+}
+
+fn get_destinations_from_configuration_file(
+    path: &Path,
+    parsers: &ParserRegistry,
+) -> io::Result<Vec<Remote>> {
+    let parser = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .and_then(|ext| parsers.get(ext).map(|parser| (ext, parser)));
+    if let Some((ext, parser)) = parser {
+        if !ParserRegistry::AMBIGUOUS_EXTENSIONS.contains(&ext) {
+            return parser(path);
+        }
+    }
+    // built-in parsers, a re-registered extension does not change what sniffing finds
+    match sniff_configuration_file(path) {
+        Some(ConfigurationFormat::Ovpn) => {
+            get_destinations_from_ovpn_file(path, parsers.remote_policy)
+        }
+        Some(ConfigurationFormat::WireGuard) => get_destinations_from_wireguard_file(path),
+        None => match parser {
+            Some((_, parser)) => parser(path),
+            None => Ok(vec![]),
+        },
+    }
+}
+
+// visited holds canonicalized dirs, so symlink loops are entered once
+fn get_destinations_from_configuration_dir(
+    path: &Path,
+    parsers: &ParserRegistry,
+    is_recursive: bool,
+    visited: &mut HashSet<PathBuf>,
+    files: &mut Vec<(PathBuf, Vec<Remote>)>,
+) -> io::Result<()> {
+    if !visited.insert(path.canonicalize()?) {
+        return Ok(());
+    }
+    for entry in read_dir(path)? {
+        let path = entry?.path();
+        if path.is_hidden() {
+            continue;
+        }
+        if path.is_file() {
+            let destinations = get_destinations_from_configuration_file(&path, parsers)?;
+            files.push((path, destinations));
+        } else if is_recursive && path.is_dir() {
+            get_destinations_from_configuration_dir(&path, parsers, is_recursive, visited, files)?;
         }
     }
-    Ok(vec![])
+    Ok(())
 }
 
 pub fn get_destinations_from_configuration_files(
     paths: &[impl AsRef<Path>],
-) -> io::Result<Vec<String>> {
-    let mut destinations = vec![];
+    parsers: &ParserRegistry,
+    is_recursive: bool,
+) -> io::Result<Vec<(PathBuf, Vec<Remote>)>> {
+    let mut files = vec![];
+    let mut visited = HashSet::new();
     for path in paths {
         let path = path.as_ref().canonicalize()?;
         if path.is_file() {
-            destinations.extend_from_slice(&get_destinations_from_configuration_file(&path)?);
+            let destinations = get_destinations_from_configuration_file(&path, parsers)?;
+            files.push((path, destinations));
         } else if path.is_dir() {
-            for entry in read_dir(&path)? {
-                let path = entry?.path();
-                if path.is_file() && !path.is_hidden() {
-                    destinations
-                        .extend_from_slice(&get_destinations_from_configuration_file(&path)?);
-                }
+            get_destinations_from_configuration_dir(
+                &path,
+                parsers,
+                is_recursive,
+                &mut visited,
+                &mut files,
+            )?;
+        }
+    }
+    Ok(files)
+}
+
+pub fn is_address(s: &str) -> bool {
+    let mut parts = s.splitn(2, '/');
+    parts.next().is_some_and(|s| s.parse::<IpAddr>().is_ok())
+        && parts.next().is_none_or(|s| s.parse::<u8>().is_ok())
+}
+
+// ip or cidr in canonical form, paths and hostnames are returned as is
+pub fn normalize_destination(s: &str) -> io::Result<String> {
+    let s = s.trim();
+    let is_hostname = s.chars().any(|c| c.is_ascii_alphabetic()) && !s.contains(['/', ':']);
+    if s.starts_with(['/', '~', '$']) || is_hostname {
+        return Ok(s.into());
+    }
+    let err = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid destination: `{}`", s),
+        )
+    };
+    let (address, prefix) = match s.split_once('/') {
+        Some((address, prefix)) => (address, Some(prefix)),
+        None => (s, None),
+    };
+    let address = address.parse::<IpAddr>().map_err(|_| err())?;
+    match prefix {
+        Some(prefix) => {
+            let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+            match prefix.parse::<u8>() {
+                Ok(prefix) if prefix <= max_prefix => Ok(format!("{}/{}", address, prefix)),
+                _ => Err(err()),
             }
         }
+        None => Ok(address.to_string()),
     }
-    Ok(destinations)
 }
 
 #[derive(PartialEq, Eq, Hash, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Direction(String);
 
 impl<'a> Direction {
@@ -180,6 +645,10 @@ impl<'a> Direction {
         !self.is_in() && !self.is_out()
     }
 
+    pub fn is_address(&self) -> bool {
+        is_address(self.safe_unwrap())
+    }
+
     pub fn to_in_string(&self) -> String {
         format!("{}{}", Self::IN, &self.safe_unwrap())
     }
@@ -204,11 +673,13 @@ impl<S: AsRef<str>> From<S> for Direction {
 }
 
 #[derive(PartialEq, Eq, Hash, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Owner(String);
 
 impl<'a> Owner {
     pub const USER: &'a str = "u:";
     pub const GROUP: &'a str = "g:";
+    const OPERATORS: [&'a str; 4] = [">=", "<=", ">", "<"];
 
     pub fn new(s: impl Into<String>) -> Self {
         Self(s.into())
@@ -219,17 +690,60 @@ impl<'a> Owner {
     }
 
     pub fn safe_unwrap(&self) -> &str {
-        self.0
+        self.unwrap_direction()
             .trim_start_matches(Self::USER)
             .trim_start_matches(Self::GROUP)
     }
 
     pub fn is_user(&self) -> bool {
-        self.0.starts_with(Self::USER)
+        self.unwrap_direction().starts_with(Self::USER)
     }
 
     pub fn is_group(&self) -> bool {
-        self.0.starts_with(Self::GROUP)
+        self.unwrap_direction().starts_with(Self::GROUP)
+    }
+
+    // optional `in:`/`out:` prefix like `Direction`, both if none
+    pub fn is_in(&self) -> bool {
+        self.0.starts_with(Direction::IN)
+    }
+
+    pub fn is_out(&self) -> bool {
+        self.0.starts_with(Direction::OUT)
+    }
+
+    // as written in a `user`/`group` list, ids can be compared (`>= 1000`) or ranged (`1000:2000`)
+    pub fn to_rule_item(&self) -> io::Result<String> {
+        let s = self.safe_unwrap();
+        let err_invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid owner: `{}`, expected numeric id", self.0),
+            )
+        };
+        let is_id = |s: &str| s.parse::<u32>().is_ok();
+        for op in Self::OPERATORS {
+            if let Some(id) = s.strip_prefix(op) {
+                let id = id.trim();
+                if !is_id(id) {
+                    return Err(err_invalid());
+                }
+                return Ok(format!("{} {}", op, id));
+            }
+        }
+        match s.split_once(':') {
+            Some((start, end)) if is_id(start.trim()) && is_id(end.trim()) => {
+                Ok(format!("{}:{}", start.trim(), end.trim()))
+            }
+            Some(_) => Err(err_invalid()),
+            None => Ok(s.into()),
+        }
+    }
+
+    fn unwrap_direction(&self) -> &str {
+        self.0
+            .trim_start_matches(Direction::IN)
+            .trim_start_matches(Direction::OUT)
     }
 }
 
@@ -238,3 +752,129 @@ impl<S: AsRef<str>> From<S> for Owner {
         Self::new(s.as_ref())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("netlock-test-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // one host per line
+    fn parse_vpn_file(path: &Path) -> io::Result<Vec<Remote>> {
+        read_lines(path)?
+            .map(|line| Ok(Remote::new(line?.trim(), None, None)))
+            .collect()
+    }
+
+    #[test]
+    fn custom_parser_is_registered() {
+        let dir = test_dir("parser_registry");
+        std::fs::write(dir.join("a.vpn"), "192.0.2.1\n192.0.2.2\n").unwrap();
+        let mut parsers = ParserRegistry::default();
+        let files = get_destinations_from_configuration_files(&[&dir], &parsers, false).unwrap();
+        assert!(files
+            .iter()
+            .all(|(_, destinations)| destinations.is_empty()));
+        parsers.register("vpn", parse_vpn_file);
+        assert!(parsers.is_supported(&dir.join("b.vpn")));
+        let files = get_destinations_from_configuration_files(&[&dir], &parsers, false).unwrap();
+        assert_eq!(files.len(), 1);
+        let hosts = files[0].1.iter().map(|r| r.host()).collect::<Vec<_>>();
+        assert_eq!(hosts, ["192.0.2.1", "192.0.2.2"]);
+        assert!(parsers.unregister("vpn").is_some());
+        assert!(!parsers.is_supported(&dir.join("b.vpn")));
+    }
+
+    #[test]
+    fn ovpn_conf_is_sniffed() {
+        let dir = test_dir("ovpn_conf");
+        let path = dir.join("client.conf");
+        std::fs::write(
+            &path,
+            "client\ndev tun\nproto udp\nremote vpn1.example.com 1194\nremote 192.0.2.1 443 tcp\n",
+        )
+        .unwrap();
+        let files =
+            get_destinations_from_configuration_files(&[&path], &ParserRegistry::default(), false)
+                .unwrap();
+        let destinations = files[0].1.iter().map(|r| r.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            destinations,
+            [
+                "vpn1.example.com port 1194 proto udp",
+                "192.0.2.1 port 443 proto tcp",
+            ],
+        );
+    }
+
+    #[test]
+    fn unknown_extensions_are_sniffed() {
+        let dir = test_dir("sniff");
+        std::fs::write(
+            dir.join("home.vpn"),
+            "# home\n[Interface]\nPrivateKey = x\n\n[Peer]\nEndpoint = 192.0.2.1:51820\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("work.txt"),
+            "; work\nremote 192.0.2.2 1194\nremote 192.0.2.3 1194\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("notes.txt"), "nothing here\n").unwrap();
+        let mut parsers = ParserRegistry::default();
+        // re-registered extensions do not apply to sniffed files
+        parsers.register(ParserRegistry::WIREGUARD_EXTENSION, parse_vpn_file);
+        parsers.set_remote_policy(RemotePolicy::First);
+        let destinations = |name: &str| {
+            get_destinations_from_configuration_files(&[dir.join(name)], &parsers, false).unwrap()
+                [0]
+            .1
+            .iter()
+            .map(|r| r.to_string())
+            .collect::<Vec<_>>()
+        };
+        assert_eq!(destinations("home.vpn"), ["192.0.2.1 port 51820 proto udp"]);
+        assert_eq!(destinations("work.txt"), ["192.0.2.2 port 1194"]);
+        assert!(destinations("notes.txt").is_empty());
+    }
+
+    #[test]
+    fn wg_extension_is_registered() {
+        let dir = test_dir("wg_extension");
+        let path = dir.join("wg0.wg");
+        std::fs::write(&path, "Endpoint = [2001:db8::1]:51820\n").unwrap();
+        let parsers = ParserRegistry::default();
+        assert!(parsers.is_supported(&path));
+        let files = get_destinations_from_configuration_files(&[&path], &parsers, false).unwrap();
+        let hosts = files[0].1.iter().map(|r| r.host()).collect::<Vec<_>>();
+        assert_eq!(hosts, ["2001:db8::1"]);
+    }
+
+    #[test]
+    fn interface_addresses_are_parsed() {
+        let output = "\
+lo0: flags=8049<UP,LOOPBACK,RUNNING,MULTICAST> mtu 16384
+\tinet 127.0.0.1 netmask 0xff000000
+\tinet6 ::1 prefixlen 128
+en0: flags=8863<UP,BROADCAST,SMART,RUNNING,SIMPLEX,MULTICAST> mtu 1500
+\tether 00:00:00:00:00:00
+\tinet6 fe80::1%en0 prefixlen 64 secured scopeid 0x4
+\tinet 192.168.1.20 netmask 0xffffff00 broadcast 192.168.1.255
+\tinet6 2001:db8::20 prefixlen 64 autoconf secured
+";
+        let addresses = parse_interface_addresses(output)
+            .into_iter()
+            .map(|(addr, prefixlen)| format!("{}/{}", addr, prefixlen))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            addresses,
+            ["fe80::1/64", "192.168.1.20/24", "2001:db8::20/64"],
+        );
+    }
+}
@@ -1,12 +1,16 @@
+use std::convert::TryFrom;
 use std::env::var_os;
 use std::error::Error;
-use std::ffi::OsStr;
+use std::ffi::{CStr, CString, OsStr};
 use std::fmt::{self, Display, Formatter};
-use std::fs::File;
-use std::io::{self, BufRead, BufReader, ErrorKind, Lines, Write};
+use std::fs::{remove_file, rename, set_permissions, File};
+use std::io::{self, BufRead, BufReader, Lines, Read, Write};
+use std::net::IpAddr;
+use std::os::raw::{c_char, c_int};
 use std::path::{Path, PathBuf};
-use std::process::{Command, Output, Stdio};
-use std::time::SystemTime;
+use std::process::{Child, Command, Output, Stdio};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
 #[derive(Debug)]
 pub enum ExecError {
@@ -40,6 +44,57 @@ impl From<io::Error> for ExecError {
 
 pub type ExecResult<T> = Result<T, ExecError>;
 
+#[derive(Debug)]
+pub enum NetlockError {
+    Pfctl(ExecError),
+    Io(io::Error),
+    Parse(String),
+    Lockout,
+}
+
+impl NetlockError {
+    const LOCKOUT: &str = "Total lockout: nothing passes out except lan, icmp and dns";
+}
+
+impl Display for NetlockError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Pfctl(err) => err.fmt(f),
+            Self::Io(err) => err.fmt(f),
+            Self::Parse(message) => write!(f, "{}", message),
+            Self::Lockout => write!(f, "{}", Self::LOCKOUT),
+        }
+    }
+}
+
+impl Error for NetlockError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Pfctl(err) => Some(err),
+            Self::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+// a failed spawn is reported as io, not as pfctl
+impl From<ExecError> for NetlockError {
+    fn from(err: ExecError) -> Self {
+        match err {
+            ExecError::IO(err) => Self::Io(err),
+            err => Self::Pfctl(err),
+        }
+    }
+}
+
+impl From<io::Error> for NetlockError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+pub type NetlockResult<T> = Result<T, NetlockError>;
+
 pub fn exec<S1, I, S2>(program: S1, args: I) -> ExecResult<Output>
 where
     S1: AsRef<OsStr>,
@@ -53,8 +108,28 @@ where
     Ok(output)
 }
 
+pub fn exec_timeout<S1, I, S2>(program: S1, args: I, timeout: Duration) -> ExecResult<Output>
+where
+    S1: AsRef<OsStr>,
+    I: IntoIterator<Item = S2>,
+    S2: AsRef<OsStr>,
+{
+    let child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    wait_with_timeout(child, timeout)
+}
+
 #[cfg(unix)]
-pub fn exec_stdin<S1, I, S2, S3>(program: S1, args: I, input: S3) -> ExecResult<Output>
+pub fn exec_stdin_timeout<S1, I, S2, S3>(
+    program: S1,
+    args: I,
+    input: S3,
+    timeout: Duration,
+) -> ExecResult<Output>
 where
     S1: AsRef<OsStr>,
     I: IntoIterator<Item = S2>,
@@ -69,20 +144,61 @@ where
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
         .spawn()?;
-    if let Some(stdin) = child.stdin.as_mut() {
-        stdin.write_all(input.as_ref().as_bytes())?;
-        let output = child.wait_with_output()?;
-        if !output.status.success() {
-            return Err(ExecError::Status(output));
+    let input = input.as_ref().as_bytes().to_vec();
+    let stdin = child.stdin.take();
+    let writer = thread::spawn(move || match stdin {
+        Some(mut stdin) => stdin.write_all(&input),
+        None => Err(io::Error::other("Failed to open stdin")),
+    });
+    let output = wait_with_timeout(child, timeout)?;
+    writer
+        .join()
+        .unwrap_or_else(|_| Err(io::Error::other("stdin writer panicked")))?;
+    Ok(output)
+}
+
+// pipes are drained on threads, so a chatty child can't block on a full pipe
+fn wait_with_timeout(mut child: Child, timeout: Duration) -> ExecResult<Output> {
+    let read_pipe = |pipe: Option<Box<dyn Read + Send>>| {
+        thread::spawn(move || {
+            let mut buf = vec![];
+            if let Some(mut pipe) = pipe {
+                pipe.read_to_end(&mut buf)?;
+            }
+            Ok::<_, io::Error>(buf)
+        })
+    };
+    let stdout = read_pipe(child.stdout.take().map(|p| Box::new(p) as _));
+    let stderr = read_pipe(child.stderr.take().map(|p| Box::new(p) as _));
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
         }
-        Ok(output)
-    } else {
-        child.kill()?;
-        Err(ExecError::IO(io::Error::new(
-            ErrorKind::Other,
-            "Failed to open stdin",
-        )))
+        if start.elapsed() >= timeout {
+            child.kill()?;
+            child.wait()?;
+            return Err(ExecError::IO(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("Timed out after {}s", timeout.as_secs_f32()),
+            )));
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+    let join = |handle: thread::JoinHandle<io::Result<Vec<u8>>>| {
+        handle
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("pipe reader panicked")))
+    };
+    let output = Output {
+        status,
+        stdout: join(stdout)?,
+        stderr: join(stderr)?,
+    };
+    if !output.status.success() {
+        return Err(ExecError::Status(output));
     }
+    Ok(output)
 }
 
 #[cfg(unix)]
@@ -90,16 +206,106 @@ pub fn get_homepath() -> Option<PathBuf> {
     var_os("HOME").map(PathBuf::from)
 }
 
+// `struct passwd` up to `pw_dir`, the rest is never read
+#[cfg(target_os = "macos")]
+#[repr(C)]
+struct Passwd {
+    pw_name: *mut c_char,
+    pw_passwd: *mut c_char,
+    pw_uid: u32,
+    pw_gid: u32,
+    pw_change: i64,
+    pw_class: *mut c_char,
+    pw_gecos: *mut c_char,
+    pw_dir: *mut c_char,
+    pw_shell: *mut c_char,
+    pw_expire: i64,
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+#[repr(C)]
+struct Passwd {
+    pw_name: *mut c_char,
+    pw_passwd: *mut c_char,
+    pw_uid: u32,
+    pw_gid: u32,
+    pw_gecos: *mut c_char,
+    pw_dir: *mut c_char,
+    pw_shell: *mut c_char,
+}
+
+#[cfg(unix)]
+extern "C" {
+    fn getpwnam_r(
+        name: *const c_char,
+        pwd: *mut Passwd,
+        buf: *mut c_char,
+        buflen: usize,
+        result: *mut *mut Passwd,
+    ) -> c_int;
+}
+
+// home of another user from the passwd database, not `$HOME`
+#[cfg(unix)]
+pub fn get_user_homepath(name: &str) -> Option<PathBuf> {
+    use std::mem::MaybeUninit;
+    use std::os::unix::ffi::OsStrExt;
+    use std::ptr;
+
+    const ERANGE: c_int = 34;
+    let name = CString::new(name).ok()?;
+    let mut buf = vec![0 as c_char; 1024];
+    loop {
+        let mut pwd = MaybeUninit::<Passwd>::uninit();
+        let mut result = ptr::null_mut();
+        // the strings of `pwd` point into `buf`, so they are copied before it is dropped
+        let code = unsafe {
+            getpwnam_r(
+                name.as_ptr(),
+                pwd.as_mut_ptr(),
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut result,
+            )
+        };
+        if code == ERANGE && buf.len() < 1 << 20 {
+            buf.resize(buf.len() * 2, 0);
+            continue;
+        }
+        if code != 0 || result.is_null() {
+            return None;
+        }
+        let dir = unsafe { (*result).pw_dir };
+        if dir.is_null() {
+            return None;
+        }
+        let dir = unsafe { CStr::from_ptr(dir) };
+        return Some(PathBuf::from(OsStr::from_bytes(dir.to_bytes())));
+    }
+}
+
+// `~`, `~/..`, `~user` and `~user/..`, left as is if the user is unknown
 #[cfg(unix)]
 pub fn expanduser<P: AsRef<Path>>(path: P) -> PathBuf {
     let path = path.as_ref();
-    if path == Path::new("~") {
+    let s = match path.to_str() {
+        Some(s) if s.starts_with('~') => &s[1..],
+        _ => return path.to_path_buf(),
+    };
+    let (name, rest) = s.split_once('/').unwrap_or((s, ""));
+    let is_name = name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "._-".contains(c));
+    if name.starts_with('-') || !is_name {
+        return path.to_path_buf();
+    }
+    if name.is_empty() {
         get_homepath()
     } else {
-        path.strip_prefix("~/")
-            .ok()
-            .and_then(|p| get_homepath().map(|hp| hp.join(p)))
+        get_user_homepath(name)
     }
+    .filter(|hp| !hp.as_os_str().is_empty())
+    .map(|hp| if rest.is_empty() { hp } else { hp.join(rest) })
     .unwrap_or_else(|| path.to_path_buf())
 }
 
@@ -114,6 +320,47 @@ impl ExpandUser for Path {
     }
 }
 
+pub fn expandvars<P: AsRef<Path>>(path: P) -> PathBuf {
+    let path = path.as_ref();
+    let s = match path.to_str() {
+        Some(s) if s.contains('$') => s,
+        _ => return path.to_path_buf(),
+    };
+    let is_name_char = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let mut result = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(idx) = rest.find('$') {
+        result.push_str(&rest[..idx]);
+        rest = &rest[idx + 1..];
+        let (name, tail) = match rest.strip_prefix('{').and_then(|r| r.split_once('}')) {
+            Some((name, tail)) => (name, tail),
+            None => {
+                let end = rest.find(|c| !is_name_char(c)).unwrap_or(rest.len());
+                (&rest[..end], &rest[end..])
+            }
+        };
+        match var_os(name).filter(|_| !name.is_empty() && name.chars().all(is_name_char)) {
+            Some(value) => {
+                result.push_str(&value.to_string_lossy());
+                rest = tail;
+            }
+            None => result.push('$'),
+        }
+    }
+    result.push_str(rest);
+    result.into()
+}
+
+pub trait ExpandVars {
+    fn expandvars(&self) -> PathBuf;
+}
+
+impl ExpandVars for Path {
+    fn expandvars(&self) -> PathBuf {
+        expandvars(self)
+    }
+}
+
 #[cfg(unix)]
 pub fn is_executable<P: AsRef<Path>>(path: P) -> bool {
     use std::os::unix::fs::PermissionsExt;
@@ -124,6 +371,17 @@ pub fn is_executable<P: AsRef<Path>>(path: P) -> bool {
         .unwrap_or(false)
 }
 
+// owner only, group and others lose all access
+#[cfg(unix)]
+pub fn clear_go_permissions<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let path = path.as_ref();
+    let mut permissions = path.metadata()?.permissions();
+    permissions.set_mode(permissions.mode() & !0o077);
+    set_permissions(path, permissions)
+}
+
 pub trait IsExecutable {
     fn is_executable(&self) -> bool;
 }
@@ -141,7 +399,7 @@ pub fn is_hidden<P: AsRef<Path>>(path: P) -> bool {
 
     path.as_ref()
         .file_name()
-        .map_or(false, |s| s.as_bytes().starts_with(b"."))
+        .is_some_and(|s| s.as_bytes().starts_with(b"."))
 }
 
 pub trait IsHidden {
@@ -162,6 +420,295 @@ pub fn time() -> u64 {
         .unwrap_or(0)
 }
 
+// `@UNIX_TIME`, `+N[smhd]` (relative) or `HH:MM[:SS]` (local, next occurrence)
+pub fn parse_time(s: &str, now: u64, utc_offset: i64) -> Option<u64> {
+    let s = s.trim();
+    if let Some(timestamp) = s.strip_prefix('@') {
+        return timestamp.parse().ok();
+    }
+    if let Some(relative) = s.strip_prefix('+') {
+        let (n, unit) = relative.split_at(
+            relative
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(relative.len()),
+        );
+        let multiplier = match unit {
+            "" | "s" => 1,
+            "m" => 60,
+            "h" => 60 * 60,
+            "d" => 24 * 60 * 60,
+            _ => return None,
+        };
+        return n
+            .parse::<u64>()
+            .ok()?
+            .checked_mul(multiplier)?
+            .checked_add(now);
+    }
+    let parts = s
+        .split(':')
+        .map(|s| s.parse::<i64>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    let (hours, minutes, seconds) = match parts[..] {
+        [h, m] => (h, m, 0),
+        [h, m, s] => (h, m, s),
+        _ => return None,
+    };
+    if !(0..24).contains(&hours) || !(0..60).contains(&minutes) || !(0..60).contains(&seconds) {
+        return None;
+    }
+    let day = 24 * 60 * 60;
+    let local_now = i64::try_from(now).ok()? + utc_offset;
+    let mut local_time =
+        local_now - local_now.rem_euclid(day) + hours * 60 * 60 + minutes * 60 + seconds;
+    if local_time <= local_now {
+        local_time += day;
+    }
+    u64::try_from(local_time - utc_offset).ok()
+}
+
+pub fn is_expired(expiry: u64, now: u64) -> bool {
+    now >= expiry
+}
+
+pub fn redact_addresses(s: &str) -> String {
+    const REDACTED: &str = "<redacted>";
+    let is_sep = |c: char| c.is_whitespace() || ",{}()<>[]\"".contains(c);
+    let is_address = |word: &str| {
+        word.split(['%', '/'])
+            .next()
+            .is_some_and(|s| s.parse::<IpAddr>().is_ok())
+            || word.split(':').count() == 6
+                && word
+                    .split(':')
+                    .all(|s| (1..=2).contains(&s.len()) && s.chars().all(|c| c.is_ascii_hexdigit()))
+    };
+    let mut result = String::with_capacity(s.len());
+    let mut word = String::new();
+    for c in s.chars().chain(Some('\n')) {
+        if !is_sep(c) {
+            word.push(c);
+            continue;
+        }
+        if is_address(&word) {
+            result.push_str(REDACTED);
+        } else {
+            result.push_str(&word);
+        }
+        word.clear();
+        result.push(c);
+    }
+    result.pop();
+    result
+}
+
+pub fn json_escape(s: &str) -> String {
+    let mut result = String::with_capacity(s.len() + 2);
+    result.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            c if (c as u32) < 0x20 => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
+
+// writes to `<path>.tmp` next to `path` and renames it into place
+pub fn write_atomic<P: AsRef<Path>, C: AsRef<[u8]>>(path: P, contents: C) -> io::Result<()> {
+    let path = path.as_ref();
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+    let result = File::create(&tmp_path)
+        .and_then(|mut file| {
+            file.write_all(contents.as_ref())?;
+            file.sync_all()
+        })
+        .and_then(|_| rename(&tmp_path, path));
+    if result.is_err() {
+        let _ = remove_file(&tmp_path);
+    }
+    result
+}
+
+// 64-bit fnv-1a, stable across runs and platforms unlike `DefaultHasher`
+pub fn fnv1a_64(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
 pub fn read_lines<P: AsRef<Path>>(path: P) -> io::Result<Lines<BufReader<File>>> {
     Ok(BufReader::new(File::open(path)?).lines())
 }
+
+pub fn diff_lines(old: &[&str], new: &[&str]) -> Vec<String> {
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for (i, a) in old.iter().enumerate().rev() {
+        for (j, b) in new.iter().enumerate().rev() {
+            lcs[i][j] = if a == b {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    let mut diff = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() && j < new.len() {
+        if old[i] == new[j] {
+            diff.push(format!("  {}", old[i]));
+            i += 1;
+            j += 1;
+        } else if lcs[i + 1][j] >= lcs[i][j + 1] {
+            diff.push(format!("- {}", old[i]));
+            i += 1;
+        } else {
+            diff.push(format!("+ {}", new[j]));
+            j += 1;
+        }
+    }
+    diff.extend(old[i..].iter().map(|s| format!("- {}", s)));
+    diff.extend(new[j..].iter().map(|s| format!("+ {}", s)));
+    diff
+}
+
+// `diff -u` hunks with three lines of context, empty if equal
+pub fn unified_diff(old: &[&str], new: &[&str], old_name: &str, new_name: &str) -> Vec<String> {
+    const CONTEXT: usize = 3;
+    let diff = diff_lines(old, new);
+    let ops = diff
+        .iter()
+        .map(|s| (s.starts_with('-'), s.starts_with('+'), &s[2..]))
+        .collect::<Vec<_>>();
+    let changes = ops
+        .iter()
+        .enumerate()
+        .filter(|(_, (is_old, is_new, _))| *is_old || *is_new)
+        .map(|(idx, _)| idx)
+        .collect::<Vec<_>>();
+    if changes.is_empty() {
+        return vec![];
+    }
+    let mut hunks = vec![];
+    let mut idx = 0;
+    while idx < changes.len() {
+        let start = changes[idx].saturating_sub(CONTEXT);
+        let mut end = (changes[idx] + 1 + CONTEXT).min(ops.len());
+        idx += 1;
+        while idx < changes.len() && changes[idx] <= end + CONTEXT {
+            end = (changes[idx] + 1 + CONTEXT).min(ops.len());
+            idx += 1;
+        }
+        hunks.push((start, end));
+    }
+    let mut lines = vec![format!("--- {}", old_name), format!("+++ {}", new_name)];
+    for (start, end) in hunks {
+        let count = |ops: &[(bool, bool, &str)], is_old: bool| {
+            ops.iter()
+                .filter(|(o, n, _)| if is_old { !n } else { !o })
+                .count()
+        };
+        let range = |before: usize, len: usize| {
+            if len == 0 {
+                format!("{},0", before)
+            } else {
+                format!("{},{}", before + 1, len)
+            }
+        };
+        lines.push(format!(
+            "@@ -{} +{} @@",
+            range(count(&ops[..start], true), count(&ops[start..end], true)),
+            range(count(&ops[..start], false), count(&ops[start..end], false)),
+        ));
+        for (is_old, is_new, line) in &ops[start..end] {
+            let prefix = if *is_old {
+                '-'
+            } else if *is_new {
+                '+'
+            } else {
+                ' '
+            };
+            lines.push(format!("{}{}", prefix, line));
+        }
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expanduser_expands_home() {
+        let home = get_homepath().unwrap();
+        assert_eq!(expanduser("~"), home);
+        assert_eq!(expanduser("~/x"), home.join("x"));
+        assert_eq!(expanduser("/x/~"), PathBuf::from("/x/~"));
+    }
+
+    #[test]
+    fn expanduser_expands_other_users() {
+        let home = if cfg!(target_os = "macos") {
+            "/var/root"
+        } else {
+            "/root"
+        };
+        assert_eq!(expanduser("~root"), PathBuf::from(home));
+        assert_eq!(expanduser("~root/x"), PathBuf::from(home).join("x"));
+        assert_eq!(expanduser("~nosuchuser"), PathBuf::from("~nosuchuser"));
+        assert_eq!(expanduser("~nosuchuser/x"), PathBuf::from("~nosuchuser/x"));
+    }
+
+    // 2023-11-14 22:13:20 utc
+    const NOW: u64 = 1_700_000_000;
+
+    #[test]
+    fn expandvars_expands_set_variables() {
+        std::env::set_var("NETLOCK_TEST_VAR", "/lists");
+        assert_eq!(expandvars("$NETLOCK_TEST_VAR/a"), PathBuf::from("/lists/a"));
+        assert_eq!(
+            expandvars("/x/${NETLOCK_TEST_VAR}/a"),
+            PathBuf::from("/x//lists/a"),
+        );
+        assert_eq!(
+            expandvars("$NETLOCK_TEST_UNSET/a"),
+            PathBuf::from("$NETLOCK_TEST_UNSET/a"),
+        );
+        assert_eq!(expandvars("/a$/b"), PathBuf::from("/a$/b"));
+    }
+
+    #[test]
+    fn parse_time_formats() {
+        assert_eq!(parse_time("@1700003600", NOW, 0), Some(1_700_003_600));
+        assert_eq!(parse_time("+90", NOW, 0), Some(NOW + 90));
+        assert_eq!(parse_time("+30m", NOW, 0), Some(NOW + 30 * 60));
+        assert_eq!(parse_time("+2h", NOW, 0), Some(NOW + 2 * 60 * 60));
+        assert_eq!(parse_time("+1d", NOW, 0), Some(NOW + 24 * 60 * 60));
+        assert_eq!(parse_time("23:00", NOW, 0), Some(1_700_002_800));
+        assert_eq!(parse_time("23:00:30", NOW, 0), Some(1_700_002_830));
+        // already past today, so tomorrow
+        assert_eq!(parse_time("18:00", NOW, 0), Some(1_700_071_200));
+        // 23:13:20 local at utc+1
+        assert_eq!(parse_time("23:00", NOW, 60 * 60), Some(1_700_085_600));
+        for s in [
+            "", "+", "+1w", "24:00", "12:60", "12", "1:2:3:4", "@x", "noon",
+        ] {
+            assert_eq!(parse_time(s, NOW, 0), None, "{}", s);
+        }
+    }
+
+    #[test]
+    fn expiry_is_due_at_expiry_time() {
+        assert!(!is_expired(NOW + 1, NOW));
+        assert!(is_expired(NOW, NOW));
+        assert!(is_expired(NOW - 1, NOW));
+    }
+}